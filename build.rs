@@ -1,43 +1,56 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script generates `memory.x` from the `[memory]` map in
+//! `keyboard.toml` into a directory where the linker can always find it at
+//! build time, along with `storage.x`, which makes the link fail if the
+//! firmware image runs into the storage region.
+//!
+//! It also generates the keymap from `keymap.toml` and the Vial config from
+//! `vial.json`, checking that they agree with each other and the firmware,
+//! and records the git commit, time, profile and features of the build.
 //!
 //! The build script also sets the linker flags to tell it which link script to use.
 
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
 use const_gen::*;
 use xz2::read::XzEncoder;
 
+/// Keymap dimensions from `keymap.toml`
+struct KeymapLayout {
+    rows: usize,
+    cols: usize,
+    encoders: usize,
+    /// Generated actions of layer 0, by row and column
+    base_layer: Vec<Vec<String>>,
+}
+
 fn main() {
+    // Generate the keymap from `keymap.toml`
+    println!("cargo:rerun-if-changed=keymap.toml");
+    let layout = generate_keymap();
+
+    let keyboard_toml = read_keyboard_toml();
+
     // Generate vial config at the root of project
     println!("cargo:rerun-if-changed=vial.json");
-    generate_vial_config();
+    let product = read_product(&keyboard_toml);
+    let identity = read_identity(&keyboard_toml, &product);
+    generate_vial_config(&layout, &keyboard_toml, &product, &identity);
+    generate_usb_config(&product, &identity);
+
+    // Embed which build this is
+    generate_build_info();
 
-    // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
+    // Put `memory.x` and `storage.x` in our output directory and ensure
+    // they're on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
+    generate_memory_layout(&keyboard_toml, out);
     println!("cargo:rustc-link-search={}", out.display());
 
-    // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
-
     // Specify linker arguments.
 
     // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
@@ -51,11 +64,345 @@ fn main() {
     // Set the extra linker script from defmt
     println!("cargo:rustc-link-arg=-Tdefmt.x");
 
+    // Check the linked image against the storage region
+    println!("cargo:rustc-link-arg=-Tstorage.x");
+
     // Use flip-link overflow check: https://github.com/knurling-rs/flip-link
     println!("cargo:rustc-linker=flip-link");
 }
 
-fn generate_vial_config() {
+/// Reads the `keyboard.toml` that `KEYBOARD_TOML_PATH` points at, shared with RMK
+fn read_keyboard_toml() -> toml::Table {
+    println!("cargo:rerun-if-env-changed=KEYBOARD_TOML_PATH");
+    let path = env::var("KEYBOARD_TOML_PATH").unwrap_or_else(|_| "keyboard.toml".to_owned());
+    println!("cargo:rerun-if-changed={}", path);
+    fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e))
+        .parse()
+        .unwrap_or_else(|e| panic!("{} is not valid TOML: {}", path, e))
+}
+
+/// Runs git in the crate root, `None` if git or the repository isn't there
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+/// Generates the build metadata included by `src/build_info.rs`
+fn generate_build_info() {
+    // Rebuild when the checked out commit changes, `HEAD` only changes on checkouts
+    // and the branch ref on commits
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head_ref);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let commit = match git(&["rev-parse", "--short=12", "HEAD"]) {
+        Some(commit) => {
+            let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+                .is_some_and(|status| !status.is_empty());
+            if dirty {
+                format!("{}-dirty", commit)
+            } else {
+                commit
+            }
+        }
+        None => "unknown".to_owned(),
+    };
+    // The first 6 bytes of the commit, which the peripheral sends to the central
+    let commit_id: Vec<u8> = (0..6)
+        .map(|i| {
+            commit
+                .get(2 * i..2 * i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .unwrap_or(0)
+        })
+        .collect();
+
+    // Reproducible builds pin the timestamp through SOURCE_DATE_EPOCH
+    let timestamp = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse::<u32>()
+            .unwrap_or_else(|_| panic!("SOURCE_DATE_EPOCH is not a timestamp: {}", epoch)),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32,
+    };
+
+    // The feature names in Cargo.toml all use underscores, so lowercasing restores them
+    let mut features = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase())
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+
+    let version = |part: &str| -> u8 {
+        env::var(format!("CARGO_PKG_VERSION_{}", part))
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| panic!("The {} version should fit in a byte", part))
+    };
+
+    let code = format!(
+        "pub const VERSION: (u8, u8, u8) = ({}, {}, {});\n\
+         pub const GIT_COMMIT: &str = {:?};\n\
+         pub const GIT_COMMIT_ID: [u8; 6] = {:?};\n\
+         pub const BUILD_TIMESTAMP: u32 = {};\n\
+         pub const BUILD_PROFILE: &str = {:?};\n\
+         pub const BUILD_FEATURES: &[&str] = &{:?};\n",
+        version("MAJOR"),
+        version("MINOR"),
+        version("PATCH"),
+        commit,
+        commit_id,
+        timestamp,
+        env::var("PROFILE").unwrap(),
+        features
+    );
+    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("build_info_generated.rs");
+    fs::write(out_file, code).unwrap();
+}
+
+/// nRF52840 flash page size, the unit of RMK's storage sectors
+const FLASH_PAGE_SIZE: u64 = 0x1000;
+/// nRF52840 flash size
+const FLASH_SIZE: u64 = 0x10_0000;
+
+/// Generates `memory.x`, `storage.x` and the storage constants included by
+/// `src/constants.rs` from `[memory]` in keyboard.toml
+fn generate_memory_layout(keyboard_toml: &toml::Table, out: &Path) {
+    let memory = keyboard_toml
+        .get("memory")
+        .and_then(|m| m.as_table())
+        .expect("keyboard.toml: missing [memory]");
+    let get = |name: &str| -> u64 {
+        memory
+            .get(name)
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u64::try_from(v).ok())
+            .unwrap_or_else(|| panic!("keyboard.toml: [memory] needs a positive `{}`", name))
+    };
+    let app_start = get("app_start");
+    let storage_start = get("storage_start");
+    let storage_sectors = get("storage_sectors");
+    let bootloader_start = get("bootloader_start");
+    let ram_start = get("ram_start");
+    let ram_length = get("ram_length");
+    let storage_end = storage_start + storage_sectors * FLASH_PAGE_SIZE;
+
+    let mut errors = Vec::new();
+    if storage_start % FLASH_PAGE_SIZE != 0 {
+        errors.push(format!(
+            "storage_start {:#X} is not aligned to a {:#X} byte flash page",
+            storage_start, FLASH_PAGE_SIZE
+        ));
+    }
+    if storage_sectors == 0 || storage_sectors > u64::from(u8::MAX) {
+        errors.push(format!(
+            "storage_sectors should be between 1 and 255, got {}",
+            storage_sectors
+        ));
+    }
+    if storage_start <= app_start {
+        errors.push(format!(
+            "the storage region at {:#X} starts before the application at {:#X}",
+            storage_start, app_start
+        ));
+    }
+    if storage_end > bootloader_start {
+        errors.push(format!(
+            "the storage region {:#X}..{:#X} overlaps the bootloader at {:#X}",
+            storage_start, storage_end, bootloader_start
+        ));
+    }
+    if bootloader_start > FLASH_SIZE {
+        errors.push(format!(
+            "bootloader_start {:#X} is past the end of flash",
+            bootloader_start
+        ));
+    }
+    if !errors.is_empty() {
+        panic!(
+            "keyboard.toml: [memory] is not a valid flash map:\n  {}",
+            errors.join("\n  ")
+        );
+    }
+
+    // The application gets everything up to the storage region, so the
+    // linker fails with "region FLASH overflowed" if the image gets too big
+    let memory_x = format!(
+        "/* Generated by build.rs from [memory] in keyboard.toml */\n\
+         MEMORY\n\
+         {{\n  \
+           FLASH : ORIGIN = {:#010X}, LENGTH = {:#X}\n  \
+           RAM : ORIGIN = {:#010X}, LENGTH = {:#X}\n\
+         }}\n",
+        app_start,
+        storage_start - app_start,
+        ram_start,
+        ram_length
+    );
+    fs::write(out.join("memory.x"), memory_x).unwrap();
+
+    let storage_x = format!(
+        "/* Generated by build.rs from [memory] in keyboard.toml */\n\
+         ASSERT(LOADADDR(.data) + SIZEOF(.data) <= {:#010X},\n  \
+           \"The firmware image runs into the storage region, move `storage_start` in keyboard.toml\");\n",
+        storage_start
+    );
+    fs::write(out.join("storage.x"), storage_x).unwrap();
+
+    let storage_rs = format!(
+        "pub const STORAGE_START_ADDR: usize = {:#X};\n\
+         pub const STORAGE_NUM_SECTORS: u8 = {};\n",
+        storage_start, storage_sectors
+    );
+    fs::write(out.join("storage_generated.rs"), storage_rs).unwrap();
+}
+
+/// USB ids and strings, from `[keyboard]` in keyboard.toml
+struct Product {
+    name: String,
+    manufacturer: String,
+    vendor_id: u16,
+    product_id: u16,
+}
+
+fn read_product(keyboard_toml: &toml::Table) -> Product {
+    let keyboard = keyboard_toml
+        .get("keyboard")
+        .and_then(|k| k.as_table())
+        .expect("keyboard.toml: missing [keyboard]");
+    let get_str = |name: &str| -> String {
+        keyboard
+            .get(name)
+            .and_then(|v| v.as_str())
+            .unwrap_or_else(|| panic!("keyboard.toml: [keyboard] needs a `{}`", name))
+            .to_owned()
+    };
+    let get_id = |name: &str| -> u16 {
+        keyboard
+            .get(name)
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u16::try_from(v).ok())
+            .unwrap_or_else(|| panic!("keyboard.toml: [keyboard] `{}` should fit in 16 bits", name))
+    };
+    Product {
+        name: get_str("name"),
+        manufacturer: get_str("manufacturer"),
+        vendor_id: get_id("vendor_id"),
+        product_id: get_id("product_id"),
+    }
+}
+
+/// Vial needs this in the USB serial number to recognize the keyboard
+const VIAL_SERIAL_PREFIX: &str = "vial:f64c2b3c";
+
+/// Vial keyboard id and USB serial number, from `[identity]` in keyboard.toml
+struct Identity {
+    keyboard_id: Vec<u8>,
+    serial_number: String,
+    per_unit_serial: bool,
+}
+
+fn read_identity(keyboard_toml: &toml::Table, product: &Product) -> Identity {
+    let identity = keyboard_toml
+        .get("identity")
+        .and_then(|i| i.as_table())
+        .expect("keyboard.toml: missing [identity]");
+    let per_unit_serial = identity
+        .get("per_unit_serial")
+        .map(|p| {
+            p.as_bool()
+                .expect("keyboard.toml: [identity] per_unit_serial should be true or false")
+        })
+        .unwrap_or(false);
+
+    match identity.get("source").and_then(|s| s.as_str()) {
+        Some("config") => {
+            let keyboard_id: Vec<u8> = identity
+                .get("keyboard_id")
+                .and_then(|id| id.as_array())
+                .and_then(|id| {
+                    id.iter()
+                        .map(|b| b.as_integer().and_then(|b| u8::try_from(b).ok()))
+                        .collect()
+                })
+                .filter(|id: &Vec<u8>| id.len() == 8)
+                .expect("keyboard.toml: [identity] keyboard_id should be a list of 8 bytes");
+            let serial_number = identity
+                .get("serial_number")
+                .and_then(|s| s.as_str())
+                .expect("keyboard.toml: [identity] needs a `serial_number`")
+                .to_owned();
+            if !serial_number.starts_with(VIAL_SERIAL_PREFIX) {
+                panic!(
+                    "keyboard.toml: [identity] serial_number should start with \"{}\" or Vial won't find the keyboard",
+                    VIAL_SERIAL_PREFIX
+                );
+            }
+            Identity {
+                keyboard_id,
+                serial_number,
+                per_unit_serial,
+            }
+        }
+        Some("product") => {
+            let hash = fnv1a(product.name.as_bytes());
+            Identity {
+                keyboard_id: hash.to_be_bytes().to_vec(),
+                serial_number: format!("{}:{:06X}", VIAL_SERIAL_PREFIX, hash & 0xFF_FFFF),
+                per_unit_serial,
+            }
+        }
+        _ => panic!("keyboard.toml: [identity] source should be \"config\" or \"product\""),
+    }
+}
+
+/// 64-bit FNV-1a, a stable hash for deriving ids from names
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Generates the USB ids, strings and serial number settings included by `src/constants.rs`
+fn generate_usb_config(product: &Product, identity: &Identity) {
+    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("usb_generated.rs");
+    let code = format!(
+        "pub const MANUFACTURE: &str = {:?};\n\
+         pub const PRODUCT_NAME: &str = {:?};\n\
+         pub const VID: u16 = {:#06x};\n\
+         pub const PID: u16 = {:#06x};\n\
+         pub const VIAL_SERIAL_PREFIX: &str = {:?};\n\
+         pub const USB_SERIAL_NUMBER: &str = {:?};\n\
+         pub const USB_SERIAL_PER_UNIT: bool = {};\n",
+        product.manufacturer,
+        product.name,
+        product.vendor_id,
+        product.product_id,
+        VIAL_SERIAL_PREFIX,
+        identity.serial_number,
+        identity.per_unit_serial
+    );
+    fs::write(out_file, code).unwrap();
+}
+
+fn generate_vial_config(
+    layout: &KeymapLayout,
+    keyboard_toml: &toml::Table,
+    product: &Product,
+    identity: &Identity,
+) {
     // Generated vial config file
     let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("config_generated.rs");
 
@@ -69,18 +416,429 @@ fn generate_vial_config() {
         Err(e) => println!("Cannot find vial.json {:?}: {}", p, e),
     };
 
-    let vial_cfg = json::stringify(json::parse(&content).unwrap());
+    let vial_json =
+        json::parse(&content).unwrap_or_else(|e| panic!("vial.json is not valid JSON: {}", e));
+    validate_vial_config(&vial_json, layout, product);
+
+    let vial_cfg = json::stringify(vial_json);
     let mut keyboard_def_compressed: Vec<u8> = Vec::new();
     XzEncoder::new(vial_cfg.as_bytes(), 6)
         .read_to_end(&mut keyboard_def_compressed)
         .unwrap();
 
-    let keyboard_id: Vec<u8> = vec![0xB9, 0xBC, 0x09, 0xB2, 0x9D, 0x37, 0x4C, 0xEA];
+    let keyboard_id = identity.keyboard_id.clone();
+    let unlock_keys = read_unlock_keys(keyboard_toml, layout)
+        .iter()
+        .map(|(row, col)| format!("({}, {})", row, col))
+        .collect::<Vec<_>>()
+        .join(", ");
     let const_declarations = [
         const_declaration!(pub VIAL_KEYBOARD_DEF = keyboard_def_compressed),
         const_declaration!(pub VIAL_KEYBOARD_ID = keyboard_id),
+        format!(
+            "pub const VIAL_UNLOCK_KEYS: &'static [(u8, u8)] = &[{}];",
+            unlock_keys
+        ),
     ]
     .map(|s| "#[allow(clippy::redundant_static_lifetimes)]\n".to_owned() + s.as_str())
     .join("\n");
     fs::write(out_file, const_declarations).unwrap();
 }
+
+/// Reads `[security] unlock_keys` from keyboard.toml, checking every key is
+/// in the matrix and does something on the base layer
+fn read_unlock_keys(keyboard_toml: &toml::Table, layout: &KeymapLayout) -> Vec<(u8, u8)> {
+    let keys = keyboard_toml
+        .get("security")
+        .and_then(|s| s.get("unlock_keys"))
+        .and_then(|k| k.as_array())
+        .filter(|k| !k.is_empty())
+        .expect("keyboard.toml: [security] needs a non-empty `unlock_keys` list");
+
+    keys.iter()
+        .map(|key| {
+            let position = key.as_array().and_then(|p| match p.as_slice() {
+                [row, col] => Some((row.as_integer()?, col.as_integer()?)),
+                _ => None,
+            });
+            let Some((row, col)) = position else {
+                panic!("keyboard.toml: unlock key {} should be [row, col]", key);
+            };
+            let action = usize::try_from(row)
+                .ok()
+                .zip(usize::try_from(col).ok())
+                .and_then(|(row, col)| layout.base_layer.get(row)?.get(col));
+            match action {
+                None => panic!(
+                    "keyboard.toml: unlock key [{}, {}] is outside the {}x{} matrix",
+                    row, col, layout.rows, layout.cols
+                ),
+                Some(action) if action == "rmk::a!(No)" => panic!(
+                    "keyboard.toml: unlock key [{}, {}] has no action on the base layer of keymap.toml",
+                    row, col
+                ),
+                Some(_) => (row as u8, col as u8),
+            }
+        })
+        .collect()
+}
+
+fn generate_keymap() -> KeymapLayout {
+    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("keymap_generated.rs");
+
+    let content = fs::read_to_string("keymap.toml").expect("Cannot read keymap.toml");
+    let keymap: toml::Table = content
+        .parse()
+        .unwrap_or_else(|e| panic!("keymap.toml is not valid TOML: {}", e));
+
+    let layout = keymap
+        .get("layout")
+        .and_then(|l| l.as_table())
+        .expect("keymap.toml: missing [layout]");
+    let get_size = |name: &str| -> usize {
+        layout
+            .get(name)
+            .and_then(|v| v.as_integer())
+            .and_then(|v| usize::try_from(v).ok())
+            .unwrap_or_else(|| panic!("keymap.toml: [layout] needs a positive `{}`", name))
+    };
+    let (rows, cols, encoders) = (get_size("rows"), get_size("cols"), get_size("encoders"));
+
+    let layers = keymap
+        .get("layer")
+        .and_then(|l| l.as_array())
+        .filter(|l| !l.is_empty())
+        .expect("keymap.toml: needs at least one [[layer]]");
+
+    let mut keymap_layers = Vec::new();
+    let mut encoder_layers = Vec::new();
+    let mut layer_colors = Vec::new();
+    for (i, layer) in layers.iter().enumerate() {
+        let layer = layer
+            .as_table()
+            .unwrap_or_else(|| panic!("keymap.toml: layer {} is not a table", i));
+        let name = match layer.get("name").and_then(|n| n.as_str()) {
+            Some(name) => format!("layer {} (\"{}\")", i, name),
+            None => format!("layer {}", i),
+        };
+
+        let key_rows: Vec<Vec<String>> = match layer.get("keys") {
+            Some(keys) => {
+                let keys = keys
+                    .as_str()
+                    .unwrap_or_else(|| panic!("keymap.toml: `keys` of {} is not a string", name));
+                let key_rows: Vec<_> = keys
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .enumerate()
+                    .map(|(row, line)| {
+                        let entries = split_entries(line);
+                        if entries.len() != cols {
+                            panic!(
+                                "keymap.toml: row {} of {} has {} keys, expected {}",
+                                row,
+                                name,
+                                entries.len(),
+                                cols
+                            );
+                        }
+                        entries
+                            .iter()
+                            .map(|entry| parse_action(entry, &format!("row {} of {}", row, name)))
+                            .collect()
+                    })
+                    .collect();
+                if key_rows.len() != rows {
+                    panic!(
+                        "keymap.toml: {} has {} rows, expected {}",
+                        name,
+                        key_rows.len(),
+                        rows
+                    );
+                }
+                key_rows
+            }
+            None => vec![vec!["rmk::a!(No)".to_owned(); cols]; rows],
+        };
+        keymap_layers.push(key_rows);
+
+        let encoder_actions: Vec<String> = match layer.get("encoders") {
+            Some(list) => {
+                let list = list
+                    .as_array()
+                    .unwrap_or_else(|| panic!("keymap.toml: `encoders` of {} is not a list", name));
+                if list.len() != encoders {
+                    panic!(
+                        "keymap.toml: {} has {} encoders, expected {}",
+                        name,
+                        list.len(),
+                        encoders
+                    );
+                }
+                list.iter()
+                    .enumerate()
+                    .map(|(id, pair)| {
+                        let ctx = format!("encoder {} of {}", id, name);
+                        match pair.as_array().map(|p| p.as_slice()) {
+                            Some([cw, ccw]) => {
+                                let action = |v: &toml::Value| {
+                                    let entry = v.as_str().unwrap_or_else(|| {
+                                        panic!("keymap.toml: {} has a non-string action", ctx)
+                                    });
+                                    parse_action(entry, &ctx)
+                                };
+                                format!("rmk::encoder!({}, {})", action(cw), action(ccw))
+                            }
+                            _ => panic!(
+                                "keymap.toml: {} should be [clockwise, counter clockwise]",
+                                ctx
+                            ),
+                        }
+                    })
+                    .collect()
+            }
+            None => vec!["rmk::encoder!(rmk::a!(No), rmk::a!(No))".to_owned(); encoders],
+        };
+        encoder_layers.push(encoder_actions);
+
+        let color = match layer.get("color") {
+            Some(color) => color
+                .as_integer()
+                .filter(|c| (0..=0xFF_FFFF).contains(c))
+                .unwrap_or_else(|| panic!("keymap.toml: `color` of {} should be 0xRRGGBB", name)),
+            None => 0,
+        };
+        layer_colors.push(color);
+    }
+
+    let mut code = String::new();
+    code.push_str("// Generated by build.rs from keymap.toml, edit that file instead\n\n");
+    code.push_str(&format!("pub(crate) const ROW: usize = {};\n", rows));
+    code.push_str(&format!("pub(crate) const COL: usize = {};\n", cols));
+    code.push_str(&format!(
+        "pub(crate) const NUM_LAYER: usize = {};\n",
+        layers.len()
+    ));
+    code.push_str(&format!(
+        "pub(crate) const NUM_ENCODER: usize = {};\n\n",
+        encoders
+    ));
+
+    code.push_str("#[rustfmt::skip]\n");
+    code.push_str("pub const fn get_keymap() -> [[[rmk::types::action::KeyAction; COL]; ROW]; NUM_LAYER] {\n    [\n");
+    for layer in &keymap_layers {
+        code.push_str("        [\n");
+        for row in layer {
+            code.push_str(&format!("            [{}],\n", row.join(", ")));
+        }
+        code.push_str("        ],\n");
+    }
+    code.push_str("    ]\n}\n\n");
+
+    code.push_str("#[rustfmt::skip]\n");
+    code.push_str("pub const fn get_encoder_map() -> [[rmk::types::action::EncoderAction; NUM_ENCODER]; NUM_LAYER] {\n    [\n");
+    for layer in &encoder_layers {
+        code.push_str(&format!("        [{}],\n", layer.join(", ")));
+    }
+    code.push_str("    ]\n}\n\n");
+
+    code.push_str("/// Status LED color for each layer, black for layers without a `color`\n");
+    code.push_str("#[rustfmt::skip]\n");
+    code.push_str("#[allow(dead_code)] // The dongle has no status LED\n");
+    code.push_str("pub(crate) const LAYER_COLORS: [cornix_core::led::Rgb; NUM_LAYER] = [\n");
+    for color in &layer_colors {
+        code.push_str(&format!(
+            "    cornix_core::led::Rgb::new({:#04X}, {:#04X}, {:#04X}),\n",
+            color >> 16,
+            (color >> 8) & 0xFF,
+            color & 0xFF
+        ));
+    }
+    code.push_str("];\n");
+
+    fs::write(out_file, code).unwrap();
+
+    KeymapLayout {
+        rows,
+        cols,
+        encoders,
+        base_layer: keymap_layers.into_iter().next().unwrap(),
+    }
+}
+
+/// Checks that vial.json describes the matrix and USB ids the firmware is built with
+fn validate_vial_config(vial_json: &json::JsonValue, layout: &KeymapLayout, product: &Product) {
+    let mut errors = Vec::new();
+
+    let matrix = &vial_json["matrix"];
+    for (name, expected) in [("rows", layout.rows), ("cols", layout.cols)] {
+        match matrix[name].as_usize() {
+            Some(n) if n == expected => {}
+            Some(n) => errors.push(format!(
+                "matrix.{} is {}, but keymap.toml has {}",
+                name, n, expected
+            )),
+            None => errors.push(format!("matrix.{} is missing", name)),
+        }
+    }
+
+    for (name, key, expected) in [
+        ("vendorId", "vendor_id", product.vendor_id),
+        ("productId", "product_id", product.product_id),
+    ] {
+        let actual = vial_json[name]
+            .as_str()
+            .and_then(|id| u16::from_str_radix(id.trim_start_matches("0x"), 16).ok());
+        match actual {
+            Some(id) if id == expected => {}
+            Some(id) => errors.push(format!(
+                "{} is {:#06X}, but [keyboard] {} in keyboard.toml is {:#06X}",
+                name, id, key, expected
+            )),
+            None => errors.push(format!("{} is missing or not a hex string", name)),
+        }
+    }
+
+    // Every key is labelled "row,col", encoders are labelled "index,direction"
+    // with "e" as their 10th legend
+    for (row, keys) in vial_json["layouts"]["keymap"].members().enumerate() {
+        for label in keys.members().filter_map(|key| key.as_str()) {
+            let legends: Vec<&str> = label.split('\n').collect();
+            let position = legends[0].split_once(',').and_then(|(a, b)| {
+                Some((
+                    a.trim().parse::<usize>().ok()?,
+                    b.trim().parse::<usize>().ok()?,
+                ))
+            });
+            let Some((a, b)) = position else {
+                errors.push(format!(
+                    "layouts.keymap row {}: cannot parse key {:?}",
+                    row, label
+                ));
+                continue;
+            };
+            if legends.get(9) == Some(&"e") {
+                if a >= layout.encoders || b > 1 {
+                    errors.push(format!(
+                        "layouts.keymap row {}: encoder \"{},{}\" is out of range, there are {} encoders with directions 0 and 1",
+                        row, a, b, layout.encoders
+                    ));
+                }
+            } else if a >= layout.rows || b >= layout.cols {
+                errors.push(format!(
+                    "layouts.keymap row {}: key \"{},{}\" is outside the {}x{} matrix",
+                    row, a, b, layout.rows, layout.cols
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        panic!(
+            "vial.json doesn't match the firmware:\n  {}",
+            errors.join("\n  ")
+        );
+    }
+}
+
+/// Splits a row of `keymap.toml` on whitespace, keeping `LT(1, Space)` in one piece
+fn split_entries(line: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in line.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                if !current.is_empty() {
+                    entries.push(std::mem::take(&mut current));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        entries.push(current);
+    }
+    entries
+}
+
+/// Turns a `keymap.toml` entry into the `rmk` macro call building its `KeyAction`
+fn parse_action(entry: &str, ctx: &str) -> String {
+    let entry = entry.trim();
+    let (func, args) = match entry.split_once('(') {
+        Some((func, rest)) => {
+            let args = rest
+                .strip_suffix(')')
+                .unwrap_or_else(|| panic!("keymap.toml: `{}` in {} is missing a `)`", entry, ctx));
+            let args: Vec<&str> = args.split(',').map(str::trim).collect();
+            (func.trim(), Some(args))
+        }
+        None => (entry, None),
+    };
+
+    let layer = |arg: &str| -> u8 {
+        arg.parse()
+            .unwrap_or_else(|_| panic!("keymap.toml: `{}` in {} needs a layer number", entry, ctx))
+    };
+    let key = |arg: &str| -> String {
+        if arg.is_empty() || !arg.chars().all(|c| c.is_ascii_alphanumeric()) {
+            panic!("keymap.toml: `{}` in {} is not a key name", arg, ctx);
+        }
+        arg.to_owned()
+    };
+
+    match (func, args.as_deref()) {
+        ("__" | "No", None) => "rmk::a!(No)".to_owned(),
+        ("_" | "Trans", None) => "rmk::a!(Transparent)".to_owned(),
+        ("MO", Some([l])) => format!("rmk::mo!({})", layer(l)),
+        ("TG", Some([l])) => format!("rmk::tg!({})", layer(l)),
+        ("TO", Some([l])) => format!("rmk::to!({})", layer(l)),
+        ("DF", Some([l])) => format!("rmk::df!({})", layer(l)),
+        ("OSL", Some([l])) => format!("rmk::osl!({})", layer(l)),
+        ("LT", Some([l, k])) => format!("rmk::lt!({}, {})", layer(l), key(k)),
+        ("MT", Some([k, m])) => format!("rmk::mt!({}, {})", key(k), parse_modifiers(m, ctx)),
+        ("WM", Some([k, m])) => format!("rmk::wm!({}, {})", key(k), parse_modifiers(m, ctx)),
+        ("OSM", Some([m])) => format!("rmk::osm!({})", parse_modifiers(m, ctx)),
+        (k, None) => format!("rmk::k!({})", key(k)),
+        _ => panic!("keymap.toml: unknown action `{}` in {}", entry, ctx),
+    }
+}
+
+/// Turns `LCtrl|LShift` into a `ModifierCombination`
+fn parse_modifiers(mods: &str, ctx: &str) -> String {
+    // A combination only has one side for all of its modifiers
+    let mut right = None;
+    let (mut gui, mut alt, mut shift, mut ctrl) = (false, false, false, false);
+    for m in mods.split('|').map(str::trim) {
+        let (is_right, name) = match m.split_at_checked(1) {
+            Some(("L", name)) => (false, name),
+            Some(("R", name)) => (true, name),
+            _ => panic!("keymap.toml: unknown modifier `{}` in {}", m, ctx),
+        };
+        if *right.get_or_insert(is_right) != is_right {
+            panic!(
+                "keymap.toml: `{}` in {} mixes left and right modifiers, use one side",
+                mods, ctx
+            );
+        }
+        match name {
+            "Gui" => gui = true,
+            "Alt" => alt = true,
+            "Shift" => shift = true,
+            "Ctrl" => ctrl = true,
+            _ => panic!("keymap.toml: unknown modifier `{}` in {}", m, ctx),
+        }
+    }
+    format!(
+        "rmk::types::modifier::ModifierCombination::new_from({}, {}, {}, {}, {})",
+        right.unwrap_or(false),
+        gui,
+        alt,
+        shift,
+        ctrl
+    )
+}
@@ -0,0 +1,185 @@
+//! Battery voltage and charge from SAADC samples.
+
+/// How the battery voltage reaches the SAADC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatterySense {
+    /// SAADC reference voltage
+    pub reference_mv: u32,
+    /// Inverse of the SAADC gain, 6 for a gain of 1/6
+    pub gain_inverse: u32,
+    pub resolution_bits: u32,
+    /// Divider resistor between the battery and the ADC pin
+    pub divider_top_kohm: u32,
+    /// Divider resistor between the ADC pin and ground
+    pub divider_bottom_kohm: u32,
+}
+
+impl BatterySense {
+    /// Battery voltage for a raw SAADC sample
+    pub const fn millivolts(&self, raw: u16) -> u32 {
+        let full_scale_mv = self.reference_mv * self.gain_inverse;
+        let pin_mv = raw as u32 * full_scale_mv / (1 << self.resolution_bits);
+        pin_mv * (self.divider_top_kohm + self.divider_bottom_kohm) / self.divider_bottom_kohm
+    }
+}
+
+/// Resting voltage of a single cell LiPo by remaining charge, from full to empty
+pub const LIPO_DISCHARGE_CURVE: [(u16, u8); 21] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 75),
+    (3950, 70),
+    (3910, 65),
+    (3870, 60),
+    (3850, 55),
+    (3840, 50),
+    (3820, 45),
+    (3800, 40),
+    (3790, 35),
+    (3770, 30),
+    (3750, 25),
+    (3730, 20),
+    (3710, 15),
+    (3690, 10),
+    (3610, 5),
+    (3270, 0),
+];
+
+/// Percentage for `mv` on `curve`, interpolated between its points.
+/// `curve` goes from the highest voltage to the lowest.
+pub const fn percent_from_millivolts(curve: &[(u16, u8)], mv: u32) -> u8 {
+    let (top_mv, top_percent) = curve[0];
+    if mv >= top_mv as u32 {
+        return top_percent;
+    }
+    let mut i = 1;
+    while i < curve.len() {
+        let (high_mv, high_percent) = curve[i - 1];
+        let (low_mv, low_percent) = curve[i];
+        if mv >= low_mv as u32 {
+            let span_mv = (high_mv - low_mv) as u32;
+            let span_percent = (high_percent - low_percent) as u32;
+            let above_mv = mv - low_mv as u32;
+            return low_percent + (above_mv * span_percent / span_mv) as u8;
+        }
+        i += 1;
+    }
+    curve[curve.len() - 1].1
+}
+
+/// Mean of the last `N` samples
+pub struct MovingAverage<const N: usize> {
+    samples: [u16; N],
+    next: usize,
+    filled: bool,
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0; N],
+            next: 0,
+            filled: false,
+        }
+    }
+
+    /// Adds `sample` and returns the new mean. The first sample fills the whole
+    /// window, so the mean doesn't ramp up from zero after boot.
+    pub fn push(&mut self, sample: u16) -> u16 {
+        if !self.filled {
+            self.samples = [sample; N];
+            self.filled = true;
+        } else {
+            self.samples[self.next] = sample;
+        }
+        self.next = (self.next + 1) % N;
+        let sum: u32 = self.samples.iter().map(|&s| s as u32).sum();
+        (sum / N as u32) as u16
+    }
+}
+
+impl<const N: usize> Default for MovingAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_falls_from_full_to_empty() {
+        assert!(
+            LIPO_DISCHARGE_CURVE
+                .windows(2)
+                .all(|pair| pair[1].0 < pair[0].0 && pair[1].1 < pair[0].1)
+        );
+        assert_eq!(LIPO_DISCHARGE_CURVE[0].1, 100);
+        assert_eq!(LIPO_DISCHARGE_CURVE[LIPO_DISCHARGE_CURVE.len() - 1].1, 0);
+    }
+
+    #[test]
+    fn percent_on_the_curve() {
+        let curve = &LIPO_DISCHARGE_CURVE;
+        assert_eq!(percent_from_millivolts(curve, 4200), 100);
+        assert_eq!(percent_from_millivolts(curve, 3840), 50);
+        assert_eq!(percent_from_millivolts(curve, 3270), 0);
+        // Halfway between 3840mV (50%) and 3850mV (55%)
+        assert_eq!(percent_from_millivolts(curve, 3845), 52);
+    }
+
+    #[test]
+    fn percent_outside_the_curve() {
+        let curve = &LIPO_DISCHARGE_CURVE;
+        assert_eq!(percent_from_millivolts(curve, 4300), 100);
+        assert_eq!(percent_from_millivolts(curve, 3000), 0);
+        assert_eq!(percent_from_millivolts(curve, 0), 0);
+    }
+
+    #[test]
+    fn percent_never_rises_as_voltage_falls() {
+        let curve = &LIPO_DISCHARGE_CURVE;
+        let percents: Vec<u8> = (3000..=4300)
+            .rev()
+            .map(|mv| percent_from_millivolts(curve, mv))
+            .collect();
+        assert!(percents.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn millivolts_through_divider() {
+        // 0.6V reference at 1/6 gain is 3.6V full scale, a 1M/1M divider doubles it
+        let sense = BatterySense {
+            reference_mv: 600,
+            gain_inverse: 6,
+            resolution_bits: 12,
+            divider_top_kohm: 1000,
+            divider_bottom_kohm: 1000,
+        };
+        assert_eq!(sense.millivolts(0), 0);
+        assert_eq!(sense.millivolts(2048), 3600);
+        assert_eq!(sense.millivolts(4095), 7198);
+    }
+
+    #[test]
+    fn average_starts_at_the_first_sample() {
+        let mut average = MovingAverage::<4>::new();
+        assert_eq!(average.push(2000), 2000);
+        assert_eq!(average.push(2400), 2100);
+    }
+
+    #[test]
+    fn average_drops_old_samples() {
+        let mut average = MovingAverage::<4>::new();
+        average.push(1000);
+        for _ in 0..3 {
+            average.push(2000);
+        }
+        assert_eq!(average.push(2000), 2000);
+    }
+}
@@ -0,0 +1,312 @@
+//! Status LED effects. Every effect is a pure function of the time since it
+//! started, so `LedController` only has to ask for a frame on each poll tick.
+
+use crate::led::Rgb;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Effect {
+    /// A steady color
+    Solid(Rgb),
+    /// Fades in and out once every `period_ms`
+    Breathing { color: Rgb, period_ms: u32 },
+    /// On for `half_period_ms`, then off for `half_period_ms`
+    Blink { color: Rgb, half_period_ms: u32 },
+    /// Goes around the color wheel once every `period_ms`
+    Rainbow { period_ms: u32, value: u8 },
+    /// Lights up once and fades out over `duration_ms`, then ends
+    Flash { color: Rgb, duration_ms: u32 },
+}
+
+impl Effect {
+    /// The color `elapsed_ms` after the effect started, `None` once a one-shot effect is over
+    pub const fn frame(&self, elapsed_ms: u64) -> Option<Rgb> {
+        match *self {
+            Effect::Solid(color) => Some(color),
+            Effect::Breathing { color, period_ms } => {
+                if period_ms < 2 {
+                    return Some(color);
+                }
+                let period = period_ms as u64;
+                let half = period / 2;
+                let phase = elapsed_ms % period;
+                let level = if phase < half {
+                    phase * 255 / half
+                } else {
+                    (period - phase) * 255 / (period - half)
+                };
+                Some(color.scale(level as u8))
+            }
+            Effect::Blink {
+                color,
+                half_period_ms,
+            } => {
+                if half_period_ms == 0 || (elapsed_ms / half_period_ms as u64).is_multiple_of(2) {
+                    Some(color)
+                } else {
+                    Some(Rgb::BLACK)
+                }
+            }
+            Effect::Rainbow { period_ms, value } => {
+                let hue = if period_ms == 0 {
+                    0
+                } else {
+                    (elapsed_ms % period_ms as u64) * 256 / period_ms as u64
+                };
+                Some(Rgb::from_hsv(hue as u8, 255, value))
+            }
+            Effect::Flash { color, duration_ms } => {
+                if elapsed_ms >= duration_ms as u64 {
+                    None
+                } else {
+                    Some(color.scale((255 - elapsed_ms * 255 / duration_ms as u64) as u8))
+                }
+            }
+        }
+    }
+}
+
+/// What an effect is shown for. When several are running, the last one in
+/// this list wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Lock indicators configured to sit below the layer color
+    LockUnder,
+    Layer,
+    /// Lock indicators configured to replace the layer color
+    LockOver,
+    /// BLE advertising
+    Connection,
+    /// Short-lived feedback, like a profile switch or the battery level
+    Indication,
+    BatteryWarning,
+    /// The halves refused each other's firmware
+    SplitError,
+}
+
+impl Priority {
+    pub const COUNT: usize = Priority::SplitError as usize + 1;
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    effect: Effect,
+    start_ms: u64,
+    end_ms: Option<u64>,
+}
+
+/// One running effect per `Priority`
+pub struct EffectStack {
+    slots: [Option<Slot>; Priority::COUNT],
+}
+
+impl Default for EffectStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EffectStack {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; Priority::COUNT],
+        }
+    }
+
+    /// Runs `effect` at `priority` from `now_ms` on. Setting the effect that's
+    /// already running there keeps its animation going.
+    pub fn set(&mut self, priority: Priority, effect: Effect, now_ms: u64) {
+        match &self.slots[priority as usize] {
+            Some(slot) if slot.effect == effect && slot.end_ms.is_none() => {}
+            _ => {
+                self.slots[priority as usize] = Some(Slot {
+                    effect,
+                    start_ms: now_ms,
+                    end_ms: None,
+                })
+            }
+        }
+    }
+
+    /// Runs `effect` at `priority` for `duration_ms`, replacing what's there
+    pub fn set_for(&mut self, priority: Priority, effect: Effect, now_ms: u64, duration_ms: u32) {
+        self.slots[priority as usize] = Some(Slot {
+            effect,
+            start_ms: now_ms,
+            end_ms: Some(now_ms + duration_ms as u64),
+        });
+    }
+
+    pub fn clear(&mut self, priority: Priority) {
+        self.slots[priority as usize] = None;
+    }
+
+    pub fn is_active(&self, priority: Priority) -> bool {
+        self.slots[priority as usize].is_some()
+    }
+
+    /// The color of the highest running effect at `now_ms`, black if there's none.
+    /// Effects that are over get removed.
+    pub fn frame(&mut self, now_ms: u64) -> Rgb {
+        let mut color = None;
+        for slot in self.slots.iter_mut().rev() {
+            let Some(running) = *slot else {
+                continue;
+            };
+            let frame = if running.end_ms.is_some_and(|end| now_ms >= end) {
+                None
+            } else {
+                running
+                    .effect
+                    .frame(now_ms.saturating_sub(running.start_ms))
+            };
+            match frame {
+                Some(frame) if color.is_none() => color = Some(frame),
+                Some(_) => {}
+                None => *slot = None,
+            }
+        }
+        color.unwrap_or(Rgb::BLACK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb = Rgb::new(0xFF, 0xFF, 0xFF);
+    const BLUE: Rgb = Rgb::new(0, 0, 0xFF);
+
+    #[test]
+    fn solid_never_changes() {
+        let solid = Effect::Solid(WHITE);
+        assert_eq!(solid.frame(0), Some(WHITE));
+        assert_eq!(solid.frame(123_456), Some(WHITE));
+    }
+
+    #[test]
+    fn breathing_fades_in_and_out() {
+        let breathing = Effect::Breathing {
+            color: WHITE,
+            period_ms: 1000,
+        };
+        assert_eq!(breathing.frame(0), Some(Rgb::BLACK));
+        assert_eq!(breathing.frame(250), Some(Rgb::new(0x7F, 0x7F, 0x7F)));
+        assert_eq!(breathing.frame(500), Some(WHITE));
+        assert_eq!(breathing.frame(750), Some(Rgb::new(0x7F, 0x7F, 0x7F)));
+        assert_eq!(breathing.frame(1000), Some(Rgb::BLACK));
+        // Too short to animate
+        let short = Effect::Breathing {
+            color: WHITE,
+            period_ms: 1,
+        };
+        assert_eq!(short.frame(7), Some(WHITE));
+    }
+
+    #[test]
+    fn blink_toggles_every_half_period() {
+        let blink = Effect::Blink {
+            color: WHITE,
+            half_period_ms: 100,
+        };
+        assert_eq!(blink.frame(0), Some(WHITE));
+        assert_eq!(blink.frame(99), Some(WHITE));
+        assert_eq!(blink.frame(100), Some(Rgb::BLACK));
+        assert_eq!(blink.frame(199), Some(Rgb::BLACK));
+        assert_eq!(blink.frame(200), Some(WHITE));
+    }
+
+    #[test]
+    fn rainbow_goes_around_once_per_period() {
+        let rainbow = Effect::Rainbow {
+            period_ms: 256,
+            value: 255,
+        };
+        assert_eq!(rainbow.frame(0), Some(Rgb::new(255, 0, 0)));
+        assert_eq!(rainbow.frame(85), Some(Rgb::from_hsv(85, 255, 255)));
+        assert_eq!(rainbow.frame(256), rainbow.frame(0));
+    }
+
+    #[test]
+    fn flash_fades_out_and_ends() {
+        let flash = Effect::Flash {
+            color: WHITE,
+            duration_ms: 100,
+        };
+        assert_eq!(flash.frame(0), Some(WHITE));
+        assert_eq!(flash.frame(50), Some(Rgb::new(0x80, 0x80, 0x80)));
+        assert_eq!(flash.frame(100), None);
+    }
+
+    #[test]
+    fn empty_stack_is_black() {
+        assert_eq!(EffectStack::new().frame(0), Rgb::BLACK);
+    }
+
+    #[test]
+    fn highest_priority_wins() {
+        let mut stack = EffectStack::new();
+        stack.set(Priority::Layer, Effect::Solid(BLUE), 0);
+        stack.set(Priority::BatteryWarning, Effect::Solid(Rgb::RED), 0);
+        assert_eq!(stack.frame(10), Rgb::RED);
+
+        stack.clear(Priority::BatteryWarning);
+        assert_eq!(stack.frame(20), BLUE);
+        assert!(!stack.is_active(Priority::BatteryWarning));
+    }
+
+    #[test]
+    fn setting_the_same_effect_keeps_its_animation() {
+        let blink = Effect::Blink {
+            color: WHITE,
+            half_period_ms: 100,
+        };
+        let mut stack = EffectStack::new();
+        stack.set(Priority::Connection, blink, 0);
+        stack.set(Priority::Connection, blink, 150);
+        assert_eq!(stack.frame(150), Rgb::BLACK);
+
+        // Another effect starts over
+        stack.set(Priority::Connection, Effect::Solid(BLUE), 150);
+        stack.set(Priority::Connection, blink, 150);
+        assert_eq!(stack.frame(150), WHITE);
+    }
+
+    #[test]
+    fn timed_effect_ends_and_uncovers_the_one_below() {
+        let mut stack = EffectStack::new();
+        stack.set(Priority::Layer, Effect::Solid(BLUE), 0);
+        stack.set_for(Priority::Indication, Effect::Solid(WHITE), 0, 100);
+        assert_eq!(stack.frame(99), WHITE);
+        assert_eq!(stack.frame(100), BLUE);
+        assert!(!stack.is_active(Priority::Indication));
+    }
+
+    #[test]
+    fn finished_one_shot_is_removed() {
+        let mut stack = EffectStack::new();
+        let flash = Effect::Flash {
+            color: WHITE,
+            duration_ms: 100,
+        };
+        stack.set(Priority::Indication, flash, 0);
+        assert!(stack.is_active(Priority::Indication));
+        assert_eq!(stack.frame(100), Rgb::BLACK);
+        assert!(!stack.is_active(Priority::Indication));
+    }
+
+    #[test]
+    fn covered_effects_keep_running() {
+        let mut stack = EffectStack::new();
+        let flash = Effect::Flash {
+            color: BLUE,
+            duration_ms: 100,
+        };
+        stack.set(Priority::Layer, flash, 0);
+        stack.set(Priority::BatteryWarning, Effect::Solid(Rgb::RED), 0);
+        // The flash ends under the warning and is gone once the warning clears
+        assert_eq!(stack.frame(150), Rgb::RED);
+        assert!(!stack.is_active(Priority::Layer));
+    }
+}
@@ -0,0 +1,293 @@
+//! WS2812 colors and their encoding into PWM duty words.
+//!
+//! The PWM runs at 16MHz with `BIT_TICKS` ticks per bit. Every bit is one duty
+//! word: the output stays high for `T1H` ticks for a 1 and `T0H` ticks for a 0.
+
+/// PWM ticks per bit, 1.25us at 16MHz
+pub const BIT_TICKS: u16 = 20;
+/// Duty = 13/20 ticks (0.8us/1.25us) for a 1
+pub const T1H: u16 = 13;
+/// Duty 7/20 ticks (0.4us/1.25us) for a 0
+pub const T0H: u16 = 7;
+/// Low for the whole bit, ends the frame
+pub const RES: u16 = 0;
+
+/// PWM duty words per pixel, one for each bit of G, R and B
+pub const WORDS_PER_PIXEL: usize = 24;
+
+/// The lowest brightness the brightness keys go to. Brightness scales the colors
+/// before gamma correction, so any lower and a full channel ends up nearly dark.
+pub const MIN_BRIGHTNESS: u8 = 48;
+
+/// A 24-bit color
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const RED: Rgb = Rgb::new(0xFF, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scales every channel by `level / 255`
+    pub const fn scale(self, level: u8) -> Self {
+        let level = level as u16;
+        Self::new(
+            (self.r as u16 * level / 255) as u8,
+            (self.g as u16 * level / 255) as u8,
+            (self.b as u16 * level / 255) as u8,
+        )
+    }
+
+    /// Applies the gamma correction table to every channel
+    pub const fn gamma_corrected(self) -> Self {
+        Self::new(
+            GAMMA[self.r as usize],
+            GAMMA[self.g as usize],
+            GAMMA[self.b as usize],
+        )
+    }
+
+    /// Converts from hue, saturation and value, all in 0..=255
+    pub const fn from_hsv(hue: u8, sat: u8, val: u8) -> Self {
+        let (sat, val) = (sat as u16, val as u16);
+        let region = hue / 43;
+        let rem = (hue as u16 - region as u16 * 43) * 6;
+        let p = (val * (255 - sat) / 255) as u8;
+        let q = (val * (255 - sat * rem / 255) / 255) as u8;
+        let t = (val * (255 - sat * (255 - rem) / 255) / 255) as u8;
+        let v = val as u8;
+        match region {
+            0 => Self::new(v, t, p),
+            1 => Self::new(q, v, p),
+            2 => Self::new(p, v, t),
+            3 => Self::new(p, q, v),
+            4 => Self::new(t, p, v),
+            _ => Self::new(v, p, q),
+        }
+    }
+}
+
+/// Gamma 2.8 correction, so equal steps in color values look like equal steps in brightness
+#[rustfmt::skip]
+const GAMMA: [u8; 256] = [
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   1,   1,   1,   1,
+      1,   1,   1,   1,   1,   1,   1,   1,   1,   2,   2,   2,   2,   2,   2,   2,
+      2,   3,   3,   3,   3,   3,   3,   3,   4,   4,   4,   4,   4,   5,   5,   5,
+      5,   6,   6,   6,   6,   7,   7,   7,   7,   8,   8,   8,   9,   9,   9,  10,
+     10,  10,  11,  11,  11,  12,  12,  13,  13,  13,  14,  14,  15,  15,  16,  16,
+     17,  17,  18,  18,  19,  19,  20,  20,  21,  21,  22,  22,  23,  24,  24,  25,
+     25,  26,  27,  27,  28,  29,  29,  30,  31,  32,  32,  33,  34,  35,  35,  36,
+     37,  38,  39,  39,  40,  41,  42,  43,  44,  45,  46,  47,  48,  49,  50,  50,
+     51,  52,  54,  55,  56,  57,  58,  59,  60,  61,  62,  63,  64,  66,  67,  68,
+     69,  70,  72,  73,  74,  75,  77,  78,  79,  81,  82,  83,  85,  86,  87,  89,
+     90,  92,  93,  95,  96,  98,  99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+    115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+    144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+    215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+/// Maps a battery percentage to a red (empty) - yellow - green (full) gradient
+pub const fn battery_color(percent: u8) -> Rgb {
+    let percent = if percent > 100 { 100 } else { percent as u16 };
+    if percent >= 50 {
+        Rgb::new(((100 - percent) * 0xFF / 50) as u8, 0xFF, 0)
+    } else {
+        Rgb::new(0xFF, (percent * 0xFF / 50) as u8, 0)
+    }
+}
+
+/// Where the lock indicators sit relative to the layer color
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LockPriority {
+    /// Locks replace the layer color
+    OverLayer,
+    /// Locks are only shown on layers whose color is black
+    UnderLayer,
+}
+
+/// Colors of the host lock indicators and Caps Word, black disables an indicator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LockIndicators {
+    /// Caps Lock and Caps Word
+    pub caps: Rgb,
+    pub num: Rgb,
+    pub scroll: Rgb,
+    pub priority: LockPriority,
+}
+
+impl LockIndicators {
+    pub const NONE: LockIndicators = LockIndicators {
+        caps: Rgb::BLACK,
+        num: Rgb::BLACK,
+        scroll: Rgb::BLACK,
+        priority: LockPriority::UnderLayer,
+    };
+}
+
+/// Encodes one pixel into WS2812 duty words, GRB order, MSB first.
+pub const fn encode_pixel(color: Rgb) -> [u16; WORDS_PER_PIXEL] {
+    let mut words = [T0H; WORDS_PER_PIXEL];
+    let grb = (color.g as u32) << 16 | (color.r as u32) << 8 | color.b as u32;
+    let mut i = 0;
+    while i < WORDS_PER_PIXEL {
+        if grb & (1 << (WORDS_PER_PIXEL - 1 - i)) != 0 {
+            words[i] = T1H;
+        }
+        i += 1;
+    }
+    words
+}
+
+/// Encodes a chain of pixels into `words`, followed by a single reset word.
+///
+/// Returns the number of words written. `words` must hold at least
+/// `pixels.len() * WORDS_PER_PIXEL + 1` entries.
+pub const fn encode_pixels(pixels: &[Rgb], words: &mut [u16]) -> usize {
+    let mut p = 0;
+    while p < pixels.len() {
+        let pixel = encode_pixel(pixels[p]);
+        let mut i = 0;
+        while i < WORDS_PER_PIXEL {
+            words[p * WORDS_PER_PIXEL + i] = pixel[i];
+            i += 1;
+        }
+        p += 1;
+    }
+    words[pixels.len() * WORDS_PER_PIXEL] = RES;
+    pixels.len() * WORDS_PER_PIXEL + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of `ticks` PWM ticks at 16MHz
+    fn ns(ticks: u16) -> u32 {
+        ticks as u32 * 1000 / 16
+    }
+
+    /// Bit `i` of a pixel in transmission order, decoded from its duty word
+    fn bit(words: &[u16], i: usize) -> bool {
+        match words[i] {
+            T1H => true,
+            T0H => false,
+            word => panic!("word {} is {}, not a WS2812 bit", i, word),
+        }
+    }
+
+    #[test]
+    fn duty_words_match_ws2812_timing() {
+        // WS2812B datasheet: T0H 0.4us, T1H 0.8us, both +-150ns, in a 1.25us +-600ns bit
+        assert!(ns(T0H).abs_diff(400) <= 150, "T0H is {}ns", ns(T0H));
+        assert!(ns(T1H).abs_diff(800) <= 150, "T1H is {}ns", ns(T1H));
+        assert!(ns(BIT_TICKS).abs_diff(1250) <= 600);
+        // The low parts, T0L 0.85us and T1L 0.45us
+        assert!(ns(BIT_TICKS - T0H).abs_diff(850) <= 150);
+        assert!(ns(BIT_TICKS - T1H).abs_diff(450) <= 150);
+        assert_eq!(RES, 0);
+    }
+
+    #[test]
+    fn pixel_is_grb_msb_first() {
+        let words = encode_pixel(Rgb::new(0x0F, 0x80, 0x01));
+        let bits: Vec<bool> = (0..WORDS_PER_PIXEL).map(|i| bit(&words, i)).collect();
+        let byte = |from: usize| {
+            bits[from..from + 8]
+                .iter()
+                .fold(0u8, |byte, &bit| byte << 1 | bit as u8)
+        };
+        assert_eq!(byte(0), 0x80, "green");
+        assert_eq!(byte(8), 0x0F, "red");
+        assert_eq!(byte(16), 0x01, "blue");
+    }
+
+    #[test]
+    fn black_and_white_pixels() {
+        assert!(encode_pixel(Rgb::BLACK).iter().all(|&w| w == T0H));
+        assert!(
+            encode_pixel(Rgb::new(0xFF, 0xFF, 0xFF))
+                .iter()
+                .all(|&w| w == T1H)
+        );
+    }
+
+    #[test]
+    fn chain_is_pixels_then_reset() {
+        let pixels = [Rgb::BLACK, Rgb::new(0xFF, 0xFF, 0xFF), Rgb::RED];
+        let mut words = [0xFFFF; 3 * WORDS_PER_PIXEL + 2];
+        let len = encode_pixels(&pixels, &mut words);
+
+        assert_eq!(len, 3 * WORDS_PER_PIXEL + 1);
+        for (p, pixel) in pixels.iter().enumerate() {
+            let range = p * WORDS_PER_PIXEL..(p + 1) * WORDS_PER_PIXEL;
+            assert_eq!(words[range], encode_pixel(*pixel), "pixel {}", p);
+        }
+        assert_eq!(words[len - 1], RES);
+        // Nothing past the reset word is touched
+        assert_eq!(words[len], 0xFFFF);
+    }
+
+    #[test]
+    fn empty_chain_is_only_reset() {
+        let mut words = [0xFFFF; 1];
+        assert_eq!(encode_pixels(&[], &mut words), 1);
+        assert_eq!(words[0], RES);
+    }
+
+    #[test]
+    fn gamma_is_monotonic_and_keeps_the_ends() {
+        assert!(GAMMA.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(GAMMA[0], 0);
+        assert_eq!(GAMMA[255], 255);
+        assert_eq!(
+            Rgb::new(0xFF, 0x80, 0).gamma_corrected(),
+            Rgb::new(0xFF, GAMMA[0x80], 0)
+        );
+    }
+
+    #[test]
+    fn scale_by_level() {
+        let white = Rgb::new(0xFF, 0xFF, 0xFF);
+        assert_eq!(white.scale(0xFF), white);
+        assert_eq!(white.scale(0), Rgb::BLACK);
+        assert_eq!(
+            Rgb::new(0xFF, 0x80, 0x10).scale(0x80),
+            Rgb::new(0x80, 0x40, 0x08)
+        );
+    }
+
+    #[test]
+    fn min_brightness_stays_lit() {
+        let dimmest = Rgb::RED.scale(MIN_BRIGHTNESS).gamma_corrected();
+        assert!(dimmest.r > 1, "{:?}", dimmest);
+    }
+
+    #[test]
+    fn hsv_primaries() {
+        assert_eq!(Rgb::from_hsv(0, 255, 255), Rgb::new(255, 0, 0));
+        assert_eq!(Rgb::from_hsv(85, 255, 255), Rgb::new(3, 255, 0));
+        assert_eq!(Rgb::from_hsv(171, 255, 255), Rgb::new(0, 3, 255));
+        // No saturation is grey at the given value
+        assert_eq!(Rgb::from_hsv(0, 0, 0x40), Rgb::new(0x40, 0x40, 0x40));
+    }
+
+    #[test]
+    fn battery_gradient() {
+        assert_eq!(battery_color(100), Rgb::new(0, 0xFF, 0));
+        assert_eq!(battery_color(50), Rgb::new(0xFF, 0xFF, 0));
+        assert_eq!(battery_color(0), Rgb::new(0xFF, 0, 0));
+        assert_eq!(battery_color(200), battery_color(100));
+    }
+}
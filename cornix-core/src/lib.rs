@@ -0,0 +1,9 @@
+//! The parts of the Cornix firmware that don't touch the hardware. They build
+//! for the host as well, where `cargo make test` runs their tests.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod battery;
+pub mod effect;
+pub mod led;
+pub mod split;
@@ -0,0 +1,250 @@
+//! Messages from the peripheral to the central, on top of RMK's split link.
+//!
+//! RMK forwards the events of the peripheral's input devices to the central, so
+//! the messages ride in `Event::Custom` payloads. Every message starts with its
+//! kind, the protocol and the sender's peripheral id. These three bytes stay the
+//! same in every protocol, the rest of the layout goes with `PROTOCOL`.
+//!
+//! The central refuses a peripheral that speaks another protocol or whose matrix
+//! doesn't sit where the central expects it in the keymap, see `check_hello`, and
+//! one that sends no `Hello` within `HELLO_TIMEOUT_MS`.
+
+/// Size of an `Event::Custom` payload
+pub const PAYLOAD_LEN: usize = 16;
+
+/// Bumped whenever the layout of a message changes
+pub const PROTOCOL: u8 = 1;
+
+/// The central refuses a peripheral that hasn't sent a `Hello` this long after
+/// connecting
+pub const HELLO_TIMEOUT_MS: u64 = 3000;
+
+const HELLO: u8 = 0xC1;
+
+/// Where a half's matrix sits in the keymap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Placement {
+    pub rows: u8,
+    pub cols: u8,
+    pub row_offset: u8,
+    pub col_offset: u8,
+}
+
+impl Placement {
+    pub const fn new(rows: usize, cols: usize, row_offset: usize, col_offset: usize) -> Self {
+        Self {
+            rows: rows as u8,
+            cols: cols as u8,
+            row_offset: row_offset as u8,
+            col_offset: col_offset as u8,
+        }
+    }
+}
+
+/// A firmware build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+    /// The first 6 bytes of the git commit, zeros if it's unknown
+    pub commit: [u8; 6],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Message {
+    /// Sent by the peripheral whenever it connects to the central
+    Hello {
+        peripheral_id: u8,
+        version: Version,
+        placement: Placement,
+    },
+    /// A message from a peripheral that speaks another protocol
+    Unsupported { peripheral_id: u8, protocol: u8 },
+}
+
+impl Message {
+    /// The sender's split peripheral id
+    pub const fn peripheral_id(&self) -> u8 {
+        match *self {
+            Message::Hello { peripheral_id, .. } | Message::Unsupported { peripheral_id, .. } => {
+                peripheral_id
+            }
+        }
+    }
+
+    pub const fn encode(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0; PAYLOAD_LEN];
+        match *self {
+            Message::Hello {
+                peripheral_id,
+                version,
+                placement,
+            } => {
+                payload[0] = HELLO;
+                payload[1] = PROTOCOL;
+                payload[2] = peripheral_id;
+                payload[3] = version.major;
+                payload[4] = version.minor;
+                payload[5] = version.patch;
+                let mut i = 0;
+                while i < version.commit.len() {
+                    payload[6 + i] = version.commit[i];
+                    i += 1;
+                }
+                payload[12] = placement.rows;
+                payload[13] = placement.cols;
+                payload[14] = placement.row_offset;
+                payload[15] = placement.col_offset;
+            }
+            // Only ever decoded
+            Message::Unsupported {
+                peripheral_id,
+                protocol,
+            } => {
+                payload[0] = HELLO;
+                payload[1] = protocol;
+                payload[2] = peripheral_id;
+            }
+        }
+        payload
+    }
+
+    /// `None` if the payload isn't one of these messages
+    pub fn decode(payload: &[u8; PAYLOAD_LEN]) -> Option<Self> {
+        let [kind, protocol, peripheral_id, ..] = *payload;
+        if kind != HELLO {
+            return None;
+        }
+        if protocol != PROTOCOL {
+            return Some(Message::Unsupported {
+                peripheral_id,
+                protocol,
+            });
+        }
+        let mut commit = [0; 6];
+        commit.copy_from_slice(&payload[6..12]);
+        Some(Message::Hello {
+            peripheral_id,
+            version: Version {
+                major: payload[3],
+                minor: payload[4],
+                patch: payload[5],
+                commit,
+            },
+            placement: Placement {
+                rows: payload[12],
+                cols: payload[13],
+                row_offset: payload[14],
+                col_offset: payload[15],
+            },
+        })
+    }
+}
+
+/// Why the central refuses a peripheral
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mismatch {
+    /// The peripheral speaks this protocol
+    Protocol(u8),
+    /// The peripheral's matrix sits here
+    Placement(Placement),
+}
+
+/// Checks a peripheral's message against the placement the central expects for it
+pub fn check_hello(message: &Message, expected: Placement) -> Result<(), Mismatch> {
+    match *message {
+        Message::Unsupported { protocol, .. } => Err(Mismatch::Protocol(protocol)),
+        Message::Hello { placement, .. } if placement != expected => {
+            Err(Mismatch::Placement(placement))
+        }
+        Message::Hello { .. } => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: Version = Version {
+        major: 1,
+        minor: 2,
+        patch: 3,
+        commit: [0x5d, 0x60, 0xa2, 0xa0, 0x12, 0x34],
+    };
+    const RIGHT: Placement = Placement::new(4, 7, 0, 7);
+
+    fn hello(placement: Placement) -> Message {
+        Message::Hello {
+            peripheral_id: 1,
+            version: VERSION,
+            placement,
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = hello(RIGHT);
+        assert_eq!(Message::decode(&hello.encode()), Some(hello));
+    }
+
+    #[test]
+    fn header_layout() {
+        let payload = hello(RIGHT).encode();
+        assert_eq!(payload[..3], [HELLO, PROTOCOL, 1]);
+    }
+
+    #[test]
+    fn other_protocol_is_unsupported() {
+        let mut payload = hello(RIGHT).encode();
+        payload[1] = PROTOCOL + 1;
+        assert_eq!(
+            Message::decode(&payload),
+            Some(Message::Unsupported {
+                peripheral_id: 1,
+                protocol: PROTOCOL + 1
+            })
+        );
+    }
+
+    #[test]
+    fn other_payloads_are_not_messages() {
+        assert_eq!(Message::decode(&[0; PAYLOAD_LEN]), None);
+        assert_eq!(Message::decode(&[0xFF; PAYLOAD_LEN]), None);
+    }
+
+    #[test]
+    fn matching_placement_passes() {
+        assert_eq!(check_hello(&hello(RIGHT), RIGHT), Ok(()));
+    }
+
+    #[test]
+    fn other_placement_is_refused() {
+        let left = Placement::new(4, 7, 0, 0);
+        assert_eq!(
+            check_hello(&hello(left), RIGHT),
+            Err(Mismatch::Placement(left))
+        );
+        let bigger = Placement::new(5, 7, 0, 7);
+        assert_eq!(
+            check_hello(&hello(bigger), RIGHT),
+            Err(Mismatch::Placement(bigger))
+        );
+    }
+
+    #[test]
+    fn other_protocol_is_refused() {
+        let unsupported = Message::Unsupported {
+            peripheral_id: 1,
+            protocol: PROTOCOL + 1,
+        };
+        assert_eq!(
+            check_hello(&unsupported, RIGHT),
+            Err(Mismatch::Protocol(PROTOCOL + 1))
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! Key activity on a half without a keymap.
+//!
+//! The central's keyboard publishes a `ControllerEvent::Key` for every key, which
+//! the status LED and deep sleep use to tell whether the keyboard is in use. The
+//! peripheral only forwards its keys to the central, so `KeyActivity` publishes
+//! them there.
+
+use defmt::unwrap;
+use rmk::channel::{CONTROLLER_CHANNEL, ControllerPub};
+use rmk::event::{ControllerEvent, Event};
+use rmk::input_device::InputDevice;
+use rmk::types::action::KeyAction;
+
+/// Wraps the matrix, publishing its keys to the controllers before they go to the central
+pub struct KeyActivity<D: InputDevice> {
+    device: D,
+    publisher: ControllerPub,
+}
+
+impl<D: InputDevice> KeyActivity<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            publisher: unwrap!(CONTROLLER_CHANNEL.publisher()),
+        }
+    }
+}
+
+impl<D: InputDevice> InputDevice for KeyActivity<D> {
+    async fn read_event(&mut self) -> Event {
+        let event = self.device.read_event().await;
+        if let Event::Key(key) = event {
+            // Without a keymap there's no action for the key
+            self.publisher
+                .publish_immediate(ControllerEvent::Key(key, KeyAction::No));
+        }
+        event
+    }
+}
@@ -0,0 +1,78 @@
+//! Battery level from the SAADC, through a LiPo discharge curve.
+//!
+//! Readings are averaged over the last few samples, turned into the battery
+//! voltage with the physical ADC and divider settings, and then into a
+//! percentage by interpolating the discharge curve.
+
+use core::cell::RefCell;
+
+use cornix_core::battery::{BatterySense, MovingAverage, percent_from_millivolts};
+use defmt::{info, unwrap};
+use rmk::channel::{CONTROLLER_CHANNEL, ControllerPub};
+use rmk::event::{ControllerEvent, Event};
+use rmk::input_device::{InputProcessor, ProcessResult};
+use rmk::keymap::KeyMap;
+
+/// Number of samples averaged, `NrfAdc` takes one every 12 seconds
+const AVERAGE_WINDOW: usize = 8;
+
+/// Replaces RMK's `BatteryProcessor`, which maps the ADC linearly to a percentage
+pub struct BatteryMonitor<
+    'a,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+    const NUM_ENCODER: usize,
+> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>>,
+    publisher: ControllerPub,
+    sense: BatterySense,
+    curve: &'static [(u16, u8)],
+    average: MovingAverage<AVERAGE_WINDOW>,
+    percent: Option<u8>,
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize, const NUM_ENCODER: usize>
+    BatteryMonitor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+{
+    pub fn new(
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>>,
+        sense: BatterySense,
+        curve: &'static [(u16, u8)],
+    ) -> Self {
+        Self {
+            keymap,
+            publisher: unwrap!(CONTROLLER_CHANNEL.publisher()),
+            sense,
+            curve,
+            average: MovingAverage::new(),
+            percent: None,
+        }
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize, const NUM_ENCODER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+    for BatteryMonitor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        match event {
+            Event::Battery(raw) => {
+                let mv = self.sense.millivolts(self.average.push(raw));
+                let percent = percent_from_millivolts(self.curve, mv);
+                if self.percent != Some(percent) {
+                    info!("Battery at {}mV, {}%", mv, percent);
+                    self.percent = Some(percent);
+                    self.publisher
+                        .publish_immediate(ControllerEvent::Battery(percent));
+                }
+                ProcessResult::Stop
+            }
+            _ => ProcessResult::Continue(event),
+        }
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>> {
+        self.keymap
+    }
+}
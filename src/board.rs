@@ -0,0 +1,461 @@
+//! Cornix hardware bring-up, shared by both halves and the dongle.
+//!
+//! `init_central`, `init_peripheral` and `init_dongle` start the chip, MPSL and the SoftDevice
+//! Controller, and hand out everything else on the board as ready to use resources.
+//!
+//! Every firmware only calls its own `init_*`, so the other roles' items are
+//! allowed to be dead one by one.
+
+use core::fmt::Write as _;
+
+use defmt::unwrap;
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{AnyPin, Input, Output};
+use embassy_nrf::interrupt::{self, InterruptExt};
+use embassy_nrf::mode::Async;
+use embassy_nrf::peripherals::{PWM0, RNG, SAADC, USBD};
+use embassy_nrf::saadc::{self, AnyInput, Saadc};
+use embassy_nrf::usb::Driver;
+use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
+use embassy_nrf::{Peri, bind_interrupts, rng, usb};
+use nrf_mpsl::Flash;
+use nrf_sdc::mpsl::MultiprotocolServiceLayer;
+use nrf_sdc::{self as sdc, SoftdeviceController, mpsl};
+use rand_chacha::ChaCha12Rng;
+use rand_core::SeedableRng;
+use rmk::config::KeyboardUsbConfig;
+use static_cell::StaticCell;
+
+use crate::constants::{
+    INPUT_PIN_NUM, KEYBOARD_USB_CONFIG, L2CAP_MTU, L2CAP_RXQ, L2CAP_TXQ, OUTPUT_PIN_NUM,
+    PERIPHERAL_IS_LEFT, USB_SERIAL_PER_UNIT, VIAL_SERIAL_PREFIX,
+};
+
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<USBD>;
+    SAADC => saadc::InterruptHandler;
+    RNG => rng::InterruptHandler<RNG>;
+    EGU0_SWI0 => nrf_sdc::mpsl::LowPrioInterruptHandler;
+    CLOCK_POWER => nrf_sdc::mpsl::ClockInterruptHandler, usb::vbus_detect::InterruptHandler;
+    RADIO => nrf_sdc::mpsl::HighPrioInterruptHandler;
+    TIMER0 => nrf_sdc::mpsl::HighPrioInterruptHandler;
+    RTC0 => nrf_sdc::mpsl::HighPrioInterruptHandler;
+});
+
+/// Size of the SoftDevice Controller's memory pool
+const SDC_MEM_SIZE: usize = 8192;
+
+/// What the BLE stack is built from, pass these to `rmk::ble::build_ble_stack`
+pub struct Ble {
+    pub sdc: SoftdeviceController<'static>,
+    pub addr: [u8; 6],
+    pub rng: ChaCha12Rng,
+}
+
+#[allow(dead_code)] // The dongle has no pins of its own
+pub struct MatrixPins {
+    pub input: [Input<'static>; INPUT_PIN_NUM],
+    pub output: [Output<'static>; OUTPUT_PIN_NUM],
+}
+
+#[allow(dead_code)] // The dongle has no pins of its own
+pub struct EncoderPins {
+    pub a: Input<'static>,
+    pub b: Input<'static>,
+}
+
+/// The status LED is a WS2812 driven by PWM0 on `data`, its power rail is switched by `power`
+#[allow(dead_code)] // The dongle has no pins of its own
+pub struct LedPins {
+    pub pwm: Peri<'static, PWM0>,
+    pub data: Peri<'static, AnyPin>,
+    pub power: Peri<'static, AnyPin>,
+}
+
+/// Numbers of the pins that System OFF has to set up, as `pin_number` gives them.
+/// The drivers of these pins are owned by RMK's matrix and the LED controller by then.
+#[allow(dead_code)] // The dongle has no pins of its own
+#[derive(Clone, Copy)]
+pub struct SleepPins {
+    /// Matrix inputs, they wake the chip when a key pulls them high
+    pub rows: [u8; INPUT_PIN_NUM],
+    /// Matrix outputs, driven high so any key can wake the chip
+    pub cols: [u8; OUTPUT_PIN_NUM],
+    pub led_power: u8,
+}
+
+/// The pins of one half, built by `pin_map!`
+pub struct PinMap {
+    pub matrix: MatrixPins,
+    pub encoder: EncoderPins,
+    pub led_data: Peri<'static, AnyPin>,
+    pub led_power: Peri<'static, AnyPin>,
+    pub battery_adc: AnyInput<'static>,
+    /// Low while the battery is charging, `None` if the half can't tell
+    pub charge_detect: Option<Input<'static>>,
+    pub sleep: SleepPins,
+}
+
+/// Number of a pin named like `P1_09`, 32 per port
+pub const fn pin_number(name: &str) -> u8 {
+    match name.as_bytes() {
+        [
+            b'P',
+            port @ b'0'..=b'1',
+            b'_',
+            tens @ b'0'..=b'3',
+            ones @ b'0'..=b'9',
+        ] => {
+            let pin = (*tens - b'0') * 10 + (*ones - b'0');
+            assert!(pin < 32, "Not a pin of the nRF52840");
+            (*port - b'0') * 32 + pin
+        }
+        _ => panic!("Pins are named like P1_09"),
+    }
+}
+
+/// Fails the build if a pin map names a pin twice
+pub const fn assert_unique_pins(pins: &[&str]) {
+    let mut i = 0;
+    while i < pins.len() {
+        let mut j = i + 1;
+        while j < pins.len() {
+            if str_eq(pins[i], pins[j]) {
+                panic!("A pin is used twice in a pin map, see `board.rs`");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Resources of the half that runs the keyboard, the left one unless `right_central` is set
+#[allow(dead_code)] // Only for the central
+pub struct Central {
+    pub ble: Ble,
+    pub flash: Flash<'static>,
+    pub usb: Driver<'static, USBD, HardwareVbusDetect>,
+    /// Calibrated ADC on the battery voltage divider
+    pub battery_adc: Saadc<'static, 1>,
+    /// Low while the battery is charging
+    pub charging: Option<Input<'static>>,
+    pub matrix: MatrixPins,
+    pub encoder: EncoderPins,
+    pub led: LedPins,
+    pub sleep: SleepPins,
+}
+
+/// Resources of a spare nRF52840 board that runs the keyboard over USB, with both
+/// halves as its peripherals
+#[allow(dead_code)] // Only for the dongle
+pub struct Dongle {
+    pub ble: Ble,
+    pub flash: Flash<'static>,
+    pub usb: Driver<'static, USBD, HardwareVbusDetect>,
+}
+
+/// Resources of the half that sends its key events to the central
+#[allow(dead_code)] // Only for the peripheral
+pub struct Peripheral {
+    pub ble: Ble,
+    pub flash: Flash<'static>,
+    /// Calibrated ADC on the battery voltage divider
+    pub battery_adc: Saadc<'static, 1>,
+    pub matrix: MatrixPins,
+    pub encoder: EncoderPins,
+    pub led: LedPins,
+    pub sleep: SleepPins,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Central,
+    Peripheral,
+    Dongle,
+}
+
+#[embassy_executor::task]
+async fn mpsl_task(mpsl: &'static MultiprotocolServiceLayer<'static>) -> ! {
+    mpsl.run().await
+}
+
+fn init_chip() -> embassy_nrf::Peripherals {
+    let mut nrf_config = embassy_nrf::config::Config::default();
+    nrf_config.dcdc.reg0_voltage = Some(embassy_nrf::config::Reg0Voltage::_3V3);
+    nrf_config.dcdc.reg0 = true;
+    nrf_config.dcdc.reg1 = true;
+    embassy_nrf::init(nrf_config)
+}
+
+/// Starts MPSL on its own task
+fn init_mpsl(
+    spawner: Spawner,
+    p: mpsl::Peripherals<'static>,
+) -> &'static MultiprotocolServiceLayer<'static> {
+    let lfclk_cfg = mpsl::raw::mpsl_clock_lfclk_cfg_t {
+        source: mpsl::raw::MPSL_CLOCK_LF_SRC_RC as u8,
+        rc_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_CTIV as u8,
+        rc_temp_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_TEMP_CTIV as u8,
+        accuracy_ppm: mpsl::raw::MPSL_DEFAULT_CLOCK_ACCURACY_PPM as u16,
+        skip_wait_lfclk_started: mpsl::raw::MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
+    };
+
+    static MPSL: StaticCell<MultiprotocolServiceLayer> = StaticCell::new();
+    static SESSION_MEM: StaticCell<mpsl::SessionMem<1>> = StaticCell::new();
+
+    let mpsl = MPSL.init(unwrap!(mpsl::MultiprotocolServiceLayer::with_timeslots(
+        p,
+        Irqs,
+        lfclk_cfg,
+        SESSION_MEM.init(mpsl::SessionMem::new())
+    )));
+    spawner.must_spawn(mpsl_task(&*mpsl));
+    mpsl
+}
+
+fn build_sdc<'d, const N: usize>(
+    role: Role,
+    p: nrf_sdc::Peripherals<'d>,
+    rng: &'d mut rng::Rng<RNG, Async>,
+    mpsl: &'d MultiprotocolServiceLayer,
+    mem: &'d mut sdc::Mem<N>,
+) -> Result<SoftdeviceController<'d>, nrf_sdc::Error> {
+    let builder = sdc::Builder::new()?;
+    let builder = match role {
+        Role::Central => builder
+            .support_scan()?
+            .support_central()?
+            .support_adv()?
+            .support_peripheral()?
+            .support_dle_peripheral()?
+            .support_dle_central()?
+            .support_phy_update_central()?
+            .support_phy_update_peripheral()?
+            .support_le_2m_phy()?
+            .central_count(1)?
+            .peripheral_count(1)?,
+        Role::Dongle => builder
+            .support_scan()?
+            .support_central()?
+            .support_adv()?
+            .support_peripheral()?
+            .support_dle_peripheral()?
+            .support_dle_central()?
+            .support_phy_update_central()?
+            .support_phy_update_peripheral()?
+            .support_le_2m_phy()?
+            .central_count(2)?
+            .peripheral_count(1)?,
+        Role::Peripheral => builder
+            .support_adv()?
+            .support_peripheral()?
+            .support_dle_peripheral()?
+            .support_phy_update_peripheral()?
+            .support_le_2m_phy()?
+            .peripheral_count(1)?,
+    };
+    builder
+        .buffer_cfg(L2CAP_MTU as u16, L2CAP_MTU as u16, L2CAP_TXQ, L2CAP_RXQ)?
+        .build(p, rng, mpsl, mem)
+}
+
+fn init_ble(
+    role: Role,
+    sdc_p: sdc::Peripherals<'static>,
+    rng: Peri<'static, RNG>,
+    mpsl: &'static MultiprotocolServiceLayer<'static>,
+) -> Ble {
+    static RNG_DRIVER: StaticCell<rng::Rng<'static, RNG, Async>> = StaticCell::new();
+    static SDC_MEM: StaticCell<sdc::Mem<SDC_MEM_SIZE>> = StaticCell::new();
+
+    let rng = RNG_DRIVER.init(rng::Rng::new(rng, Irqs));
+    let rng_generator = ChaCha12Rng::from_rng(&mut *rng).unwrap();
+    let sdc_mem = SDC_MEM.init(sdc::Mem::new());
+    let sdc = unwrap!(build_sdc(role, sdc_p, rng, mpsl, sdc_mem));
+    Ble {
+        sdc,
+        addr: ble_addr(),
+        rng: rng_generator,
+    }
+}
+
+/// Initializes the SAADC peripheral in single-ended mode on the given pin, and calibrates it.
+async fn init_adc(adc_pin: AnyInput<'static>, adc: Peri<'static, SAADC>) -> Saadc<'static, 1> {
+    // We are only using one channel, for detecting battery level.
+    // Resolution, gain and reference have to match `BATTERY_SENSE`.
+    let mut config = saadc::Config::default();
+    config.resolution = saadc::Resolution::_12BIT;
+    let mut channel_cfg = saadc::ChannelConfig::single_ended(adc_pin);
+    channel_cfg.gain = saadc::Gain::GAIN1_6;
+    channel_cfg.reference = saadc::Reference::INTERNAL;
+    interrupt::SAADC.set_priority(interrupt::Priority::P3);
+    let saadc = saadc::Saadc::new(adc, Irqs, config, [channel_cfg]);
+    saadc.calibrate().await;
+    saadc
+}
+
+/// USB config, with a serial number made from the chip's device id if `per_unit_serial`
+/// is set in keyboard.toml
+#[allow(dead_code)] // The peripheral has no USB
+pub fn usb_config() -> KeyboardUsbConfig<'static> {
+    if !USB_SERIAL_PER_UNIT {
+        return KEYBOARD_USB_CONFIG;
+    }
+    static SERIAL_NUMBER: StaticCell<heapless::String<32>> = StaticCell::new();
+    let ficr = embassy_nrf::pac::FICR;
+    let device_id = u64::from(ficr.deviceid(1).read()) << 32 | u64::from(ficr.deviceid(0).read());
+    let serial_number = SERIAL_NUMBER.init(heapless::String::new());
+    unwrap!(write!(
+        serial_number,
+        "{}:{:016X}",
+        VIAL_SERIAL_PREFIX, device_id
+    ));
+    KeyboardUsbConfig {
+        serial_number: serial_number.as_str(),
+        ..KEYBOARD_USB_CONFIG
+    }
+}
+
+fn ble_addr() -> [u8; 6] {
+    let ficr = embassy_nrf::pac::FICR;
+    let high = u64::from(ficr.deviceid(1).read());
+    let addr = high << 32 | u64::from(ficr.deviceid(0).read());
+    let addr = addr | 0x0000_c000_0000_0000;
+    unwrap!(addr.to_le_bytes()[..6].try_into())
+}
+
+/// Pins of the left half
+macro_rules! left_pins {
+    ($p:ident) => {
+        pin_map! {
+            peripherals: $p,
+            matrix_input: [P0_30, P0_31, P0_29, P0_02],
+            matrix_output: [P0_28, P0_03, P1_10, P1_11, P1_13, P0_09, P0_10],
+            encoder: [P1_06, P1_04],
+            led_data: P0_24,
+            led_power: P0_13,
+            battery_adc: P0_05, // another name: AI3
+            charge_detect: P1_09,
+        }
+    };
+}
+
+/// Pins of the right half. P1_09 is a matrix row here, so it has no charge detection.
+macro_rules! right_pins {
+    ($p:ident) => {
+        pin_map! {
+            peripherals: $p,
+            matrix_input: [P1_09, P0_28, P0_03, P1_10],
+            matrix_output: [P0_09, P0_10, P1_13, P0_02, P0_29, P0_31, P0_30],
+            encoder: [P1_06, P1_04],
+            led_data: P0_24,
+            led_power: P0_13,
+            battery_adc: P0_05,
+        }
+    };
+}
+
+#[allow(dead_code)] // Only for the central
+pub async fn init_central(spawner: Spawner) -> Central {
+    let p = init_chip();
+    let mpsl = init_mpsl(
+        spawner,
+        mpsl::Peripherals::new(p.RTC0, p.TIMER0, p.TEMP, p.PPI_CH19, p.PPI_CH30, p.PPI_CH31),
+    );
+    let sdc_p = sdc::Peripherals::new(
+        p.PPI_CH17, p.PPI_CH18, p.PPI_CH20, p.PPI_CH21, p.PPI_CH22, p.PPI_CH23, p.PPI_CH24,
+        p.PPI_CH25, p.PPI_CH26, p.PPI_CH27, p.PPI_CH28, p.PPI_CH29,
+    );
+    let ble = init_ble(Role::Central, sdc_p, p.RNG, mpsl);
+
+    let pins = if PERIPHERAL_IS_LEFT {
+        right_pins!(p)
+    } else {
+        left_pins!(p)
+    };
+
+    Central {
+        ble,
+        flash: Flash::take(mpsl, p.NVMC),
+        usb: Driver::new(p.USBD, Irqs, HardwareVbusDetect::new(Irqs)),
+        battery_adc: init_adc(pins.battery_adc, p.SAADC).await,
+        charging: pins.charge_detect,
+        matrix: pins.matrix,
+        encoder: pins.encoder,
+        led: LedPins {
+            pwm: p.PWM0,
+            data: pins.led_data,
+            power: pins.led_power,
+        },
+        sleep: pins.sleep,
+    }
+}
+
+#[allow(dead_code)] // Only for the peripheral
+pub async fn init_peripheral(spawner: Spawner) -> Peripheral {
+    let p = init_chip();
+    let mpsl = init_mpsl(
+        spawner,
+        mpsl::Peripherals::new(p.RTC0, p.TIMER0, p.TEMP, p.PPI_CH19, p.PPI_CH30, p.PPI_CH31),
+    );
+    let sdc_p = sdc::Peripherals::new(
+        p.PPI_CH17, p.PPI_CH18, p.PPI_CH20, p.PPI_CH21, p.PPI_CH22, p.PPI_CH23, p.PPI_CH24,
+        p.PPI_CH25, p.PPI_CH26, p.PPI_CH27, p.PPI_CH28, p.PPI_CH29,
+    );
+    let ble = init_ble(Role::Peripheral, sdc_p, p.RNG, mpsl);
+
+    let pins = if PERIPHERAL_IS_LEFT {
+        left_pins!(p)
+    } else {
+        right_pins!(p)
+    };
+
+    Peripheral {
+        ble,
+        flash: Flash::take(mpsl, p.NVMC),
+        battery_adc: init_adc(pins.battery_adc, p.SAADC).await,
+        matrix: pins.matrix,
+        encoder: pins.encoder,
+        led: LedPins {
+            pwm: p.PWM0,
+            data: pins.led_data,
+            power: pins.led_power,
+        },
+        sleep: pins.sleep,
+    }
+}
+
+#[allow(dead_code)] // Only for the dongle
+pub async fn init_dongle(spawner: Spawner) -> Dongle {
+    let p = init_chip();
+    let mpsl = init_mpsl(
+        spawner,
+        mpsl::Peripherals::new(p.RTC0, p.TIMER0, p.TEMP, p.PPI_CH19, p.PPI_CH30, p.PPI_CH31),
+    );
+    let sdc_p = sdc::Peripherals::new(
+        p.PPI_CH17, p.PPI_CH18, p.PPI_CH20, p.PPI_CH21, p.PPI_CH22, p.PPI_CH23, p.PPI_CH24,
+        p.PPI_CH25, p.PPI_CH26, p.PPI_CH27, p.PPI_CH28, p.PPI_CH29,
+    );
+    let ble = init_ble(Role::Dongle, sdc_p, p.RNG, mpsl);
+
+    Dongle {
+        ble,
+        flash: Flash::take(mpsl, p.NVMC),
+        usb: Driver::new(p.USBD, Irqs, HardwareVbusDetect::new(Irqs)),
+    }
+}
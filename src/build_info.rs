@@ -0,0 +1,25 @@
+//! Which build is flashed, generated by `build.rs`
+
+use cornix_core::split::Version;
+use defmt::info;
+
+// `VERSION`, `GIT_COMMIT`, `GIT_COMMIT_ID`, `BUILD_TIMESTAMP`, `BUILD_PROFILE` and
+// `BUILD_FEATURES`
+include!(concat!(env!("OUT_DIR"), "/build_info_generated.rs"));
+
+/// This build, as the halves tell each other
+pub const BUILD_VERSION: Version = Version {
+    major: VERSION.0,
+    minor: VERSION.1,
+    patch: VERSION.2,
+    commit: GIT_COMMIT_ID,
+};
+
+/// Logs the build, so it shows up first thing in a probe-rs session
+pub fn log_build_info() {
+    let (major, minor, patch) = VERSION;
+    info!(
+        "Cornix {}.{}.{} ({}), built at {} ({} profile) with features {}",
+        major, minor, patch, GIT_COMMIT, BUILD_TIMESTAMP, BUILD_PROFILE, BUILD_FEATURES
+    );
+}
@@ -1,22 +1,162 @@
 #![allow(unused)]
 
+use embassy_time::Duration;
+
+use cornix_core::battery::{BatterySense, LIPO_DISCHARGE_CURVE};
+use cornix_core::split::Placement;
 use rmk::config::KeyboardUsbConfig;
 
-pub const MANUFACTURE: &'static str = "Jezail Funder Studio";
-pub const PRODUCT_NAME: &'static str = "Cornix";
-pub const VID: u16 = 0xe11b;
-pub const PID: u16 = 0x0001;
+// `MANUFACTURE`, `PRODUCT_NAME`, `VID` and `PID` are generated by `build.rs` from
+// `[keyboard]` in `keyboard.toml`, and `VIAL_SERIAL_PREFIX`, `USB_SERIAL_NUMBER` and
+// `USB_SERIAL_PER_UNIT` from `[identity]`
+include!(concat!(env!("OUT_DIR"), "/usb_generated.rs"));
+
 pub const KEYBOARD_USB_CONFIG: KeyboardUsbConfig = KeyboardUsbConfig {
     vid: VID,
     pid: PID,
     manufacturer: MANUFACTURE,
     product_name: PRODUCT_NAME,
-    serial_number: "vial:f64c2b3c:000001",
+    serial_number: USB_SERIAL_NUMBER,
 };
 
+// `STORAGE_START_ADDR` and `STORAGE_NUM_SECTORS` are generated by `build.rs`,
+// according to `[memory]` in `keyboard.toml`
+include!(concat!(env!("OUT_DIR"), "/storage_generated.rs"));
+
 pub const INPUT_PIN_NUM: usize = 4;
 pub const OUTPUT_PIN_NUM: usize = 7;
 
+#[cfg(all(feature = "dongle", feature = "right_central"))]
+compile_error!("`right_central` doesn't apply to dongle builds, pick the half with `left_half`");
+#[cfg(all(feature = "left_half", not(feature = "dongle")))]
+compile_error!("`left_half` only applies to dongle builds");
+
+/// Whether the `peripheral` firmware is for the left half. It's for the right half,
+/// unless `right_central` makes the right half the central or `left_half` picks the
+/// left half for a dongle.
+pub const PERIPHERAL_IS_LEFT: bool = cfg!(any(feature = "right_central", feature = "left_half"));
+
+/// Split peripheral ids of the halves when the dongle has both as peripherals
+pub const LEFT_PERIPHERAL_ID: usize = 0;
+pub const RIGHT_PERIPHERAL_ID: usize = 1;
+
+/// Split peripheral id of the `peripheral` firmware
+pub const PERIPHERAL_ID: usize = if cfg!(feature = "dongle") && !PERIPHERAL_IS_LEFT {
+    RIGHT_PERIPHERAL_ID
+} else {
+    0
+};
+
+/// Where each half's matrix sits in the keymap
+pub const LEFT_ROW_OFFSET: usize = 0;
+pub const LEFT_COL_OFFSET: usize = 0;
+pub const RIGHT_ROW_OFFSET: usize = 0;
+pub const RIGHT_COL_OFFSET: usize = OUTPUT_PIN_NUM;
+pub const CENTRAL_ROW_OFFSET: usize = 0;
+pub const CENTRAL_COL_OFFSET: usize = if PERIPHERAL_IS_LEFT {
+    RIGHT_COL_OFFSET
+} else {
+    LEFT_COL_OFFSET
+};
+pub const PERIPHERAL_ROW_OFFSET: usize = 0;
+pub const PERIPHERAL_COL_OFFSET: usize = if PERIPHERAL_IS_LEFT {
+    LEFT_COL_OFFSET
+} else {
+    RIGHT_COL_OFFSET
+};
+
+/// Where the `peripheral` firmware's matrix sits in the keymap. It sends this to
+/// the central, which refuses the link if it expects the peripheral elsewhere.
+pub const PERIPHERAL_PLACEMENT: Placement = Placement::new(
+    INPUT_PIN_NUM,
+    OUTPUT_PIN_NUM,
+    PERIPHERAL_ROW_OFFSET,
+    PERIPHERAL_COL_OFFSET,
+);
+/// Where the dongle expects each half, by split peripheral id
+pub const DONGLE_PLACEMENTS: [Placement; 2] = {
+    let mut placements = [Placement::new(0, 0, 0, 0); 2];
+    placements[LEFT_PERIPHERAL_ID] = Placement::new(
+        INPUT_PIN_NUM,
+        OUTPUT_PIN_NUM,
+        LEFT_ROW_OFFSET,
+        LEFT_COL_OFFSET,
+    );
+    placements[RIGHT_PERIPHERAL_ID] = Placement::new(
+        INPUT_PIN_NUM,
+        OUTPUT_PIN_NUM,
+        RIGHT_ROW_OFFSET,
+        RIGHT_COL_OFFSET,
+    );
+    placements
+};
+
+/// Encoder ids in the encoder map of keymap.toml, the left encoder comes first
+const LEFT_ENCODER_ID: u8 = 0;
+const RIGHT_ENCODER_ID: u8 = 1;
+pub const CENTRAL_ENCODER_ID: u8 = if PERIPHERAL_IS_LEFT {
+    RIGHT_ENCODER_ID
+} else {
+    LEFT_ENCODER_ID
+};
+pub const PERIPHERAL_ENCODER_ID: u8 = if PERIPHERAL_IS_LEFT {
+    LEFT_ENCODER_ID
+} else {
+    RIGHT_ENCODER_ID
+};
+
+/// Number of WS2812 LEDs on each half
+pub const LED_NUM: usize = 1;
+
+/// Status LED brightness at power on
+pub const LED_BRIGHTNESS: u8 = 0x80;
+
+/// How the battery reaches the ADC on both halves. `board::init_adc` sets the SAADC up
+/// to match, the divider is 806k over 2M.
+pub const BATTERY_SENSE: BatterySense = BatterySense {
+    reference_mv: 600,
+    gain_inverse: 6,
+    resolution_bits: 12,
+    divider_top_kohm: 806,
+    divider_bottom_kohm: 2000,
+};
+
+/// Battery voltage in mV to charge in percent, from the highest voltage to the
+/// lowest. The percentage is interpolated between the points.
+pub const BATTERY_CURVE: &[(u16, u8)] = &LIPO_DISCHARGE_CURVE;
+
+// The interpolation divides by the voltage step and subtracts the percentages
+const _: () = {
+    assert!(!BATTERY_CURVE.is_empty(), "BATTERY_CURVE is empty");
+    let mut i = 1;
+    while i < BATTERY_CURVE.len() {
+        assert!(
+            BATTERY_CURVE[i].0 < BATTERY_CURVE[i - 1].0,
+            "BATTERY_CURVE voltages must strictly decrease"
+        );
+        assert!(
+            BATTERY_CURVE[i].1 < BATTERY_CURVE[i - 1].1,
+            "BATTERY_CURVE percentages must strictly decrease"
+        );
+        i += 1;
+    }
+};
+
+/// The status LED blinks red at or below this battery percentage
+pub const BATTERY_LOW_PERCENT: u8 = 15;
+
+/// The status LED stays off at or below this battery percentage
+pub const BATTERY_CRITICAL_PERCENT: u8 = 5;
+
+/// The status LED turns off after this long without key presses
+pub const LED_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A half goes to deep sleep after this long without key presses on it
+pub const SLEEP_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The peripheral goes to deep sleep after losing the central for this long
+pub const SLEEP_CENTRAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 /// How many outgoing L2CAP buffers per link
 pub const L2CAP_TXQ: u8 = 4;
 
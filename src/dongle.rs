@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+
+mod vial;
+#[macro_use]
+mod macros;
+mod board;
+mod build_info;
+mod constants;
+mod keymap;
+mod split_central;
+
+use defmt::info;
+use embassy_executor::Spawner;
+use rmk::ble::build_ble_stack;
+use rmk::channel::EVENT_CHANNEL;
+use rmk::config::{BleBatteryConfig, RmkConfig, StorageConfig};
+use rmk::futures::future::{join, join4};
+use rmk::keyboard::Keyboard;
+use rmk::split::ble::central::read_peripheral_addresses;
+use rmk::split::central::run_peripheral_manager;
+use rmk::{HostResources, initialize_encoder_keymap_and_storage, run_processor_chain, run_rmk};
+
+use {defmt_rtt as _, panic_probe as _};
+
+use crate::constants::{
+    DONGLE_PLACEMENTS, INPUT_PIN_NUM, LEFT_COL_OFFSET, LEFT_PERIPHERAL_ID, LEFT_ROW_OFFSET,
+    OUTPUT_PIN_NUM, RIGHT_COL_OFFSET, RIGHT_PERIPHERAL_ID, RIGHT_ROW_OFFSET, STORAGE_NUM_SECTORS,
+    STORAGE_START_ADDR,
+};
+use crate::keymap::{COL, NUM_ENCODER, NUM_LAYER, ROW};
+use crate::split_central::{PeripheralMonitor, until_refused};
+use crate::vial::VIAL_CONFIG;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Hello RMK BLE!");
+    build_info::log_build_info();
+    let board::Dongle {
+        mut ble,
+        flash,
+        usb,
+    } = board::init_dongle(spawner).await;
+    let mut host_resources = HostResources::new();
+    let stack = build_ble_stack(ble.sdc, ble.addr, &mut ble.rng, &mut host_resources).await;
+
+    // Keyboard config, the dongle has no battery
+    let storage_config = StorageConfig {
+        start_addr: STORAGE_START_ADDR,
+        num_sectors: STORAGE_NUM_SECTORS,
+        clear_storage: false,
+        ..Default::default()
+    };
+    let rmk_config = RmkConfig {
+        usb_config: board::usb_config(),
+        vial_config: VIAL_CONFIG,
+        ble_battery_config: BleBatteryConfig::new(None, false, None, false),
+        storage_config,
+    };
+
+    // Initialize the storage and keymap
+    let mut behavior_config = keymap::get_behavior_config();
+    let mut keymap = keymap::get_keymap();
+    let mut encoder_map = keymap::get_encoder_map();
+    let (keymap, mut storage) = initialize_encoder_keymap_and_storage(
+        &mut keymap,
+        &mut encoder_map,
+        flash,
+        &storage_config,
+        &mut behavior_config,
+    )
+    .await;
+
+    // All keys come from the halves, so there's no matrix here
+    let mut keyboard = Keyboard::new(&keymap);
+    let mut peripheral_monitor = PeripheralMonitor::new(&keymap, &DONGLE_PLACEMENTS);
+
+    // Read peripheral addresses from storage
+    let peripheral_addrs =
+        read_peripheral_addresses::<2, _, { ROW }, { COL }, { NUM_LAYER }, { NUM_ENCODER }>(
+            &mut storage,
+        )
+        .await;
+
+    // Start
+    join4(
+        run_processor_chain! {
+            EVENT_CHANNEL => [peripheral_monitor],
+        },
+        keyboard.run(),
+        join(
+            until_refused(
+                LEFT_PERIPHERAL_ID,
+                run_peripheral_manager::<
+                    INPUT_PIN_NUM,
+                    OUTPUT_PIN_NUM,
+                    LEFT_ROW_OFFSET,
+                    LEFT_COL_OFFSET,
+                    _,
+                >(
+                    LEFT_PERIPHERAL_ID,
+                    peripheral_addrs[LEFT_PERIPHERAL_ID],
+                    &stack,
+                ),
+            ),
+            until_refused(
+                RIGHT_PERIPHERAL_ID,
+                run_peripheral_manager::<
+                    INPUT_PIN_NUM,
+                    OUTPUT_PIN_NUM,
+                    RIGHT_ROW_OFFSET,
+                    RIGHT_COL_OFFSET,
+                    _,
+                >(
+                    RIGHT_PERIPHERAL_ID,
+                    peripheral_addrs[RIGHT_PERIPHERAL_ID],
+                    &stack,
+                ),
+            ),
+        ),
+        run_rmk(&keymap, usb, &stack, &mut storage, rmk_config),
+    )
+    .await;
+}
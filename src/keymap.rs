@@ -2,93 +2,37 @@ use defmt::unwrap;
 use embassy_time::Duration;
 use rmk::combo::Combo;
 use rmk::config::macro_config::KeyboardMacrosConfig;
-use rmk::config::{CombosConfig, MorsesConfig};
+use rmk::config::{BehaviorConfig, CombosConfig, MorsesConfig, TapHoldConfig};
 use rmk::heapless::Vec;
 use rmk::keyboard_macros::define_macro_sequences;
 use rmk::morse::{Morse, MorseMode, MorsePattern};
-use rmk::types::action::{Action, EncoderAction, KeyAction};
+use rmk::types::action::Action;
 use rmk::types::keycode::KeyCode;
 use rmk::types::modifier::ModifierCombination;
-use rmk::{a, encoder, k, mt, wm};
+use rmk::{k, mt, wm};
 
-pub(crate) const COL: usize = 14;
-pub(crate) const ROW: usize = 4;
-pub(crate) const NUM_LAYER: usize = 11;
-pub(crate) const NUM_ENCODER: usize = 2;
+use cornix_core::led::{LockIndicators, LockPriority, Rgb};
+
+// `ROW`, `COL`, `NUM_LAYER`, `NUM_ENCODER`, `get_keymap()`, `get_encoder_map()`
+// and `LAYER_COLORS` are generated by `build.rs`, according to `keymap.toml`
+include!(concat!(env!("OUT_DIR"), "/keymap_generated.rs"));
+
+/// Status LED colors for Caps Lock / Caps Word, Num Lock and Scroll Lock.
+/// Num Lock is on most of the time on many hosts, so the locks stay below the
+/// layer colors and show on the base layer.
+#[allow(dead_code)] // The dongle has no status LED
+pub(crate) const LOCK_INDICATORS: LockIndicators = LockIndicators {
+    caps: Rgb::new(0xFF, 0x80, 0x00),
+    num: Rgb::new(0x00, 0x80, 0xFF),
+    scroll: Rgb::new(0xFF, 0x00, 0x80),
+    priority: LockPriority::UnderLayer,
+};
 
 const MOD_G: ModifierCombination = ModifierCombination::new_from(false, true, false, false, false);
 const MOD_A: ModifierCombination = ModifierCombination::new_from(false, false, true, false, false);
 const MOD_S: ModifierCombination = ModifierCombination::new_from(false, false, false, true, false);
 const MOD_C: ModifierCombination = ModifierCombination::new_from(false, false, false, false, true);
 
-#[rustfmt::skip]
-pub const fn get_keymap() -> [[[KeyAction; COL]; ROW]; NUM_LAYER] {
-    const NO: [[KeyAction; COL]; ROW] = [
-        [a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No)],
-        [a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No)],
-        [a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No)],
-        [a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No), a!(No)],
-    ];
-
-    const BASE: [[KeyAction; COL]; ROW] = [
-         [k!(Tab)   , k!(Q)   , k!(W)   , k!(E)        , k!(R)    , k!(T)    , a!(No)   , a!(No)   , k!(Y)    , k!(U)    , k!(I)     , k!(O)             , k!(P)        , k!(Backspace),], 
-         [k!(Escape), k!(A)   , k!(S)   , k!(D)        , k!(F)    , k!(G)    , a!(No)   , k!(Space), k!(H)    , k!(J)    , k!(K)     , k!(L)             , k!(Semicolon), k!(Quote)    ,], 
-         [k!(LShift), k!(Z)   , k!(X)   , k!(C)        , k!(V)    , k!(B)    , k!(Space), a!(No)   , k!(N)    , k!(M)    , k!(Comma) , k!(Dot)           , k!(Slash)    , k!(Space)    ,], 
-         [k!(LCtrl) , k!(LAlt), k!(LGui), k!(Backspace), k!(Space), k!(Enter), a!(No)   , a!(No)   , k!(Enter), k!(Space), k!(Delete), k!(CapsWordToggle), k!(Down)     , k!(Space)    ,], 
-    ];
-
-    [BASE, NO, NO, NO, NO, NO, NO, NO, NO, NO, NO,]
-}
-
-pub const fn get_encoder_map() -> [[EncoderAction; NUM_ENCODER]; NUM_LAYER] {
-    [
-        [
-            encoder!(k!(Left), k!(Right)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(Left), k!(Right)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(Left), k!(Right)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(Left), k!(Right)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-        [
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-            encoder!(k!(KbVolumeUp), k!(KbVolumeDown)),
-        ],
-    ]
-}
-
 pub fn get_macros() -> KeyboardMacrosConfig {
     KeyboardMacrosConfig::new(define_macro_sequences(&[]))
 }
@@ -160,3 +104,20 @@ pub fn get_morses() -> MorsesConfig {
 
     MorsesConfig { morses }
 }
+
+/// Macros, combos, morses and tap-hold settings, for whichever board runs the keymap
+pub fn get_behavior_config() -> BehaviorConfig {
+    BehaviorConfig {
+        keyboard_macros: get_macros(),
+        combo: get_combos(),
+        morse: get_morses(),
+        tap_hold: TapHoldConfig {
+            enable_hrm: true,
+            prior_idle_time: Duration::from_millis(30u64),
+            timeout: Duration::from_millis(200u64),
+            mode: MorseMode::PermissiveHold,
+            unilateral_tap: true,
+        },
+        ..Default::default()
+    }
+}
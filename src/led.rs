@@ -7,52 +7,149 @@ use embassy_nrf::{
         SingleSequencer,
     },
 };
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant, Timer};
 use rmk::{
+    ble::BleState,
     channel::{CONTROLLER_CHANNEL, ControllerSub},
     controller::{Controller, PollingController},
     event::ControllerEvent,
+    types::{
+        action::{Action, KeyAction},
+        keycode::KeyCode,
+    },
+};
+
+use cornix_core::effect::{Effect, EffectStack, Priority};
+use cornix_core::led::{
+    BIT_TICKS, LockIndicators, LockPriority, MIN_BRIGHTNESS, RES, Rgb, WORDS_PER_PIXEL,
+    battery_color, encode_pixels,
 };
 
-const T1H: u16 = 0x0 | 13; // Duty = 13/20 ticks (0.8us/1.25us) for a 1
-const T0H: u16 = 0x0 | 7; // Duty 7/20 ticks (0.4us/1.25us) for a 0
-const RES: u16 = 0x0;
+use crate::split_guard;
+
+/// `BATT` in vial.json's `customKeycodes`, shows the battery level for a few seconds
+const SHOW_BATTERY_KEY: KeyCode = KeyCode::User8;
+/// `BRI_UP` and `BRI_DN` in vial.json's `customKeycodes`
+const BRIGHTNESS_UP_KEY: KeyCode = KeyCode::User9;
+const BRIGHTNESS_DOWN_KEY: KeyCode = KeyCode::User10;
+/// How much a brightness key changes the brightness
+const BRIGHTNESS_STEP: u8 = 16;
+
+/// Low battery warning, blinking red
+const BATTERY_WARNING: Effect = Effect::Blink {
+    color: Rgb::RED,
+    half_period_ms: 500,
+};
+/// Shown while the halves refuse each other's firmware, blinking red fast
+const SPLIT_ERROR: Effect = Effect::Blink {
+    color: Rgb::RED,
+    half_period_ms: 100,
+};
+/// How long the battery level stays on the LED
+const BATTERY_SHOW_MS: u32 = 3000;
+/// How long connection and output changes stay on the LED
+const CONNECTION_SHOW_MS: u32 = 2000;
+/// Breathing period of the profile color while advertising
+const ADVERTISING_PERIOD_MS: u32 = 2000;
+/// Half period of the blink after losing the host
+const DISCONNECTED_BLINK_MS: u32 = 200;
+/// Fade out time of the profile color after switching profiles
+const PROFILE_FLASH_MS: u32 = 1000;
+
+/// Colors of the BLE profiles, `BT0`, `BT1` and `BT2` in vial.json
+const PROFILE_COLORS: [Rgb; 3] = [
+    Rgb::new(0x00, 0x00, 0xFF),
+    Rgb::new(0x00, 0xFF, 0x00),
+    Rgb::new(0xFF, 0x00, 0xFF),
+];
+/// Color shown when the output is switched to USB
+const USB_COLOR: Rgb = Rgb::new(0xFF, 0xFF, 0xFF);
+
+/// Longest WS2812 chain a `LedController` can drive
+pub const MAX_CHAIN_LEN: usize = 8;
+const MAX_SEQ_LEN: usize = MAX_CHAIN_LEN * WORDS_PER_PIXEL + 1;
+
+/// Caps Word ends after this long without a key press
+const CAPS_WORD_IDLE_MS: u64 = 5000;
+
+/// Whether `key` keeps Caps Word on: letters, digits, `-`, backspace, delete and
+/// shift. Any other key ends it.
+fn continues_caps_word(key: KeyCode) -> bool {
+    let code = key as u16;
+    (KeyCode::A as u16..=KeyCode::Z as u16).contains(&code)
+        || (KeyCode::Kc1 as u16..=KeyCode::Kc0 as u16).contains(&code)
+        || matches!(
+            key,
+            KeyCode::Minus
+                | KeyCode::Backspace
+                | KeyCode::Delete
+                | KeyCode::LShift
+                | KeyCode::RShift
+        )
+}
 
-pub struct LedController<'d, T>
+/// Drives a chain of `N` WS2812 LEDs from a single PWM channel
+pub struct LedController<'d, T, const N: usize>
 where
     T: PeripheralType + Instance,
 {
     sub: ControllerSub,
     pwm: SequencePwm<'d, T>,
     seq_config: SequenceConfig,
-    color_bit: usize,
-    bit_value: u16,
-    seq_words: [u16; 10],
+    /// The frame on the chain, after brightness and gamma correction
+    pixels: [Rgb; N],
+    /// Pixels set through `set_color` or `set_pixels`, shown instead of the effects
+    /// until `clear_pixels`
+    custom: Option<[Rgb; N]>,
+    seq_words: [u16; MAX_SEQ_LEN],
+    dirty: bool,
+    /// Switches the LED power rail
+    en: Output<'d>,
+    powered: bool,
+    /// Turn the LED off this long after the last key press, never if `None`
+    idle_timeout_ms: Option<u64>,
+    last_activity_ms: u64,
+    /// Global brightness, applied to every frame before gamma correction
+    brightness: u8,
+    effects: EffectStack,
+    layer_colors: &'static [Rgb],
+    battery: Option<u8>,
+    battery_low: u8,
+    battery_cutoff: u8,
+    ble_profile: u8,
+    ble_state: BleState,
+    usb_output: bool,
+    lock_indicators: LockIndicators,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+    /// Last key press while Caps Word is on
+    caps_word: Option<u64>,
+    /// Indication to show once the current one is over
+    next_indication: Option<(Effect, u32)>,
 }
 
-impl<'d, T> LedController<'d, T>
+impl<'d, T, const N: usize> LedController<'d, T, N>
 where
     T: PeripheralType + Instance,
 {
+    const CHAIN_LEN_OK: () = assert!(N > 0 && N <= MAX_CHAIN_LEN);
+
     pub fn new<D>(pwm: Peri<'d, T>, ch0: Peri<'d, D>, en: Peri<'d, impl Pin>) -> Self
     where
         D: Pin,
     {
+        let () = Self::CHAIN_LEN_OK;
+
         let mut config = Config::default();
         config.sequence_load = SequenceLoad::Common;
         config.prescaler = Prescaler::Div1;
-        config.max_duty = 20; // 1.25us (1s / 16Mhz * 20)
-        // let pwm = unwrap!(SequencePwm::new_1ch(pwm, ch0, config));
+        config.max_duty = BIT_TICKS; // 1.25us (1s / 16Mhz * 20)
         let pwm = unwrap!(SequencePwm::new_1ch(pwm, ch0, config));
 
-        Output::new(en, Level::Low, OutputDrive::Standard).set_high();
+        // The rail is switched on when there's something to show
+        let en = Output::new(en, Level::Low, OutputDrive::Standard);
 
-        let seq_words = [
-            T0H, T0H, T0H, // G
-            T0H, T0H, T0H, // R
-            T1H, T1H, T1H, // B
-            RES,
-        ];
         let mut seq_config = SequenceConfig::default();
         seq_config.end_delay = 799; // 50us (20 ticks * 40) - 1 tick because we've already got one RES;
 
@@ -60,14 +157,262 @@ where
             sub: unwrap!(CONTROLLER_CHANNEL.subscriber()),
             pwm,
             seq_config,
-            color_bit: 16,
-            bit_value: T0H,
-            seq_words,
+            pixels: [Rgb::BLACK; N],
+            custom: None,
+            seq_words: [RES; MAX_SEQ_LEN],
+            dirty: false,
+            en,
+            powered: false,
+            idle_timeout_ms: None,
+            last_activity_ms: 0,
+            brightness: u8::MAX,
+            effects: EffectStack::new(),
+            layer_colors: &[],
+            battery: None,
+            battery_low: 0,
+            battery_cutoff: 0,
+            ble_profile: 0,
+            ble_state: BleState::None,
+            usb_output: false,
+            lock_indicators: LockIndicators::NONE,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+            caps_word: None,
+            next_indication: None,
+        }
+    }
+
+    /// Shows `layer_colors[layer]` whenever the active layer changes.
+    /// Layers without an entry leave the LED untouched.
+    pub fn with_layer_colors(mut self, layer_colors: &'static [Rgb]) -> Self {
+        self.layer_colors = layer_colors;
+        self.set_layer(0);
+        self
+    }
+
+    /// Brightness at power on, at least `MIN_BRIGHTNESS`. The brightness keys change
+    /// it until the next reset, it isn't saved to storage
+    pub fn with_brightness(mut self, brightness: u8) -> Self {
+        self.brightness = brightness.max(MIN_BRIGHTNESS);
+        self
+    }
+
+    /// Blinks red while the battery is at or below `percent`
+    pub fn with_battery_warning(mut self, percent: u8) -> Self {
+        self.battery_low = percent;
+        self
+    }
+
+    /// Keeps the LED rail off while the battery is at or below `percent`
+    pub fn with_battery_cutoff(mut self, percent: u8) -> Self {
+        self.battery_cutoff = percent;
+        self
+    }
+
+    /// Turns the LED off after `timeout` without key presses or status changes,
+    /// except for the battery warning and split errors
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout_ms = Some(timeout.as_millis());
+        self
+    }
+
+    fn is_idle(&self, now_ms: u64) -> bool {
+        self.idle_timeout_ms
+            .is_some_and(|timeout| now_ms.saturating_sub(self.last_activity_ms) >= timeout)
+            && !self.effects.is_active(Priority::BatteryWarning)
+            && !self.effects.is_active(Priority::SplitError)
+    }
+
+    fn battery_is_critical(&self) -> bool {
+        self.battery
+            .is_some_and(|level| level <= self.battery_cutoff)
+    }
+
+    /// Cuts the LED rail, the chain forgets its colors
+    fn power_off(&mut self) {
+        if self.powered {
+            self.en.set_low();
+            self.powered = false;
+        }
+        self.pixels = [Rgb::BLACK; N];
+        self.dirty = false;
+    }
+
+    async fn power_on(&mut self) {
+        if !self.powered {
+            self.en.set_high();
+            self.powered = true;
+            // Give the LEDs time to start up before sending them data
+            Timer::after(Duration::from_millis(1)).await;
+            self.dirty = true;
         }
     }
+
+    /// Shows host lock states and Caps Word with the given colors
+    pub fn with_lock_indicators(mut self, lock_indicators: LockIndicators) -> Self {
+        self.lock_indicators = lock_indicators;
+        self
+    }
+
+    fn now_ms() -> u64 {
+        Instant::now().as_millis()
+    }
+
+    fn set_layer(&mut self, layer: u8) {
+        match self.layer_colors.get(layer as usize) {
+            Some(&Rgb::BLACK) => self.effects.clear(Priority::Layer),
+            Some(&color) => self
+                .effects
+                .set(Priority::Layer, Effect::Solid(color), Self::now_ms()),
+            None => {}
+        }
+    }
+
+    fn profile_color(&self) -> Rgb {
+        PROFILE_COLORS
+            .get(self.ble_profile as usize)
+            .copied()
+            .unwrap_or(Rgb::RED)
+    }
+
+    /// Shows `effect` for `duration_ms` on top of everything but the battery warning
+    fn indicate(&mut self, effect: Effect, duration_ms: u32) {
+        self.last_activity_ms = Self::now_ms();
+        self.effects
+            .set_for(Priority::Indication, effect, Self::now_ms(), duration_ms);
+        self.next_indication = None;
+    }
+
+    fn indicate_battery(&mut self) {
+        if let Some(level) = self.battery {
+            self.indicate(Effect::Solid(battery_color(level)), BATTERY_SHOW_MS);
+        }
+    }
+
+    fn update_battery_warning(&mut self) {
+        match self.battery {
+            Some(level) if level <= self.battery_low => {
+                self.effects
+                    .set(Priority::BatteryWarning, BATTERY_WARNING, Self::now_ms())
+            }
+            _ => self.effects.clear(Priority::BatteryWarning),
+        }
+    }
+
+    fn update_advertising(&mut self) {
+        if !self.usb_output && self.ble_state == BleState::Advertising {
+            let advertising = Effect::Breathing {
+                color: self.profile_color(),
+                period_ms: ADVERTISING_PERIOD_MS,
+            };
+            self.effects
+                .set(Priority::Connection, advertising, Self::now_ms());
+        } else {
+            self.effects.clear(Priority::Connection);
+        }
+    }
+
+    /// The color of the highest active lock, caps first
+    fn lock_color(&self) -> Option<Rgb> {
+        let locks = &self.lock_indicators;
+        [
+            (self.caps_lock || self.caps_word.is_some(), locks.caps),
+            (self.num_lock, locks.num),
+            (self.scroll_lock, locks.scroll),
+        ]
+        .into_iter()
+        .find(|(on, color)| *on && *color != Rgb::BLACK)
+        .map(|(_, color)| color)
+    }
+
+    fn update_locks(&mut self) {
+        let priority = match self.lock_indicators.priority {
+            LockPriority::OverLayer => Priority::LockOver,
+            LockPriority::UnderLayer => Priority::LockUnder,
+        };
+        match self.lock_color() {
+            Some(color) => self
+                .effects
+                .set(priority, Effect::Solid(color), Self::now_ms()),
+            None => self.effects.clear(priority),
+        }
+    }
+
+    /// Follows Caps Word from the keys going through the keyboard, RMK doesn't
+    /// publish its state. `CapsWordToggle` toggles it, a key that doesn't continue
+    /// a word or `CAPS_WORD_IDLE_MS` without keys end it.
+    fn track_caps_word(&mut self, key: KeyCode, now_ms: u64) {
+        self.caps_word = match self.caps_word {
+            None if key == KeyCode::CapsWordToggle => Some(now_ms),
+            Some(_) if key != KeyCode::CapsWordToggle && continues_caps_word(key) => Some(now_ms),
+            _ => None,
+        };
+    }
+
+    fn expire_caps_word(&mut self, now_ms: u64) {
+        if self
+            .caps_word
+            .is_some_and(|last| now_ms.saturating_sub(last) >= CAPS_WORD_IDLE_MS)
+        {
+            self.caps_word = None;
+            self.update_locks();
+        }
+    }
+
+    /// Puts `frame` on the chain at the next `show`
+    fn set_frame(&mut self, frame: [Rgb; N]) {
+        if self.pixels != frame {
+            self.pixels = frame;
+            self.dirty = true;
+        }
+    }
+
+    /// Sends the current pixels to the chain
+    async fn show(&mut self) {
+        let len = encode_pixels(&self.pixels, &mut self.seq_words);
+        let sequencer = SingleSequencer::new(
+            &mut self.pwm,
+            &self.seq_words[..len],
+            self.seq_config.clone(),
+        );
+        unwrap!(sequencer.start(SingleSequenceMode::Times(1)));
+        // Dropping the sequencer stops the PWM, so wait for the frame and the reset to go out
+        Timer::after(Duration::from_micros(len as u64 * 5 / 4 + 60)).await;
+        drop(sequencer);
+        self.dirty = false;
+    }
 }
 
-impl<'a, T> Controller for LedController<'a, T>
+/// Colors set from outside replace the effects, the battery warning and split
+/// errors still show over them. They go through the brightness and gamma
+/// correction like the effects.
+#[allow(dead_code)] // No firmware sets its own colors yet
+impl<'d, T, const N: usize> LedController<'d, T, N>
+where
+    T: PeripheralType + Instance,
+{
+    /// Sets every LED in the chain to the same color
+    pub fn set_color(&mut self, r: u8, g: u8, b: u8) {
+        self.custom = Some([Rgb::new(r, g, b); N]);
+    }
+
+    /// Sets the chain pixel by pixel, extra pixels are ignored and missing ones keep
+    /// their color
+    pub fn set_pixels(&mut self, pixels: &[Rgb]) {
+        let mut custom = self.custom.unwrap_or([Rgb::BLACK; N]);
+        let len = pixels.len().min(N);
+        custom[..len].copy_from_slice(&pixels[..len]);
+        self.custom = Some(custom);
+    }
+
+    /// Goes back to showing the effects
+    pub fn clear_pixels(&mut self) {
+        self.custom = None;
+    }
+}
+
+impl<'a, T, const N: usize> Controller for LedController<'a, T, N>
 where
     T: PeripheralType + Instance,
 {
@@ -75,7 +420,84 @@ where
 
     async fn process_event(&mut self, event: Self::Event) {
         match event {
-            ControllerEvent::Key(..) => {}
+            ControllerEvent::Layer(layer) => self.set_layer(layer),
+            ControllerEvent::Battery(level) => {
+                self.battery = Some(level);
+                self.update_battery_warning();
+            }
+            ControllerEvent::BleProfile(profile) => {
+                if profile != self.ble_profile {
+                    self.ble_profile = profile;
+                    let flash = Effect::Flash {
+                        color: self.profile_color(),
+                        duration_ms: PROFILE_FLASH_MS,
+                    };
+                    self.indicate(flash, PROFILE_FLASH_MS);
+                    self.update_advertising();
+                }
+            }
+            ControllerEvent::BleState(profile, state) => {
+                let was_connected = self.ble_state == BleState::Connected;
+                self.ble_profile = profile;
+                self.ble_state = state;
+                self.update_advertising();
+                match state {
+                    BleState::Connected if !was_connected => {
+                        self.indicate(Effect::Solid(self.profile_color()), CONNECTION_SHOW_MS);
+                        if let Some(level) = self.battery {
+                            self.next_indication =
+                                Some((Effect::Solid(battery_color(level)), BATTERY_SHOW_MS));
+                        }
+                    }
+                    BleState::Advertising | BleState::None if was_connected => {
+                        let disconnected = Effect::Blink {
+                            color: self.profile_color(),
+                            half_period_ms: DISCONNECTED_BLINK_MS,
+                        };
+                        self.indicate(disconnected, CONNECTION_SHOW_MS)
+                    }
+                    _ => {}
+                }
+            }
+            // Sent when `SWITCH` changes the output
+            ControllerEvent::ConnectionType(connection_type) => {
+                self.usb_output = connection_type == 0;
+                self.update_advertising();
+                let color = if self.usb_output {
+                    USB_COLOR
+                } else {
+                    self.profile_color()
+                };
+                self.indicate(Effect::Solid(color), CONNECTION_SHOW_MS);
+            }
+            ControllerEvent::KeyboardIndicator(indicator) => {
+                self.caps_lock = indicator.caps_lock();
+                self.num_lock = indicator.num_lock();
+                self.scroll_lock = indicator.scroll_lock();
+                self.update_locks();
+            }
+            ControllerEvent::Key(event, action) if event.pressed => {
+                let now = Self::now_ms();
+                self.last_activity_ms = now;
+                if let KeyAction::Single(Action::Key(key)) = action {
+                    if key == SHOW_BATTERY_KEY {
+                        self.indicate_battery();
+                    } else if key == BRIGHTNESS_UP_KEY {
+                        self.brightness = self.brightness.saturating_add(BRIGHTNESS_STEP);
+                    } else if key == BRIGHTNESS_DOWN_KEY {
+                        self.brightness = self
+                            .brightness
+                            .saturating_sub(BRIGHTNESS_STEP)
+                            .max(MIN_BRIGHTNESS);
+                    } else {
+                        self.track_caps_word(key, now);
+                        self.update_locks();
+                    }
+                } else if self.caps_word.is_some() {
+                    // Layer and tap-hold keys don't end Caps Word
+                    self.caps_word = Some(now);
+                }
+            }
             _ => {}
         }
     }
@@ -85,19 +507,45 @@ where
     }
 }
 
-impl<'a, T> PollingController for LedController<'a, T>
+impl<'a, T, const N: usize> PollingController for LedController<'a, T, N>
 where
     T: PeripheralType + Instance,
 {
-    const INTERVAL: Duration = Duration::from_millis(100);
+    const INTERVAL: Duration = Duration::from_millis(40);
 
     async fn update(&mut self) {
-        let sequences =
-            SingleSequencer::new(&mut self.pwm, &self.seq_words, self.seq_config.clone());
-        unwrap!(sequences.start(SingleSequenceMode::Infinite));
-
-        drop(sequences);
-
-        // self.seq_words[self.color_bit] = self.bit_value;
+        let now = Self::now_ms();
+        self.expire_caps_word(now);
+        match (
+            split_guard::is_refused(),
+            self.effects.is_active(Priority::SplitError),
+        ) {
+            (true, false) => self.effects.set(Priority::SplitError, SPLIT_ERROR, now),
+            (false, true) => self.effects.clear(Priority::SplitError),
+            _ => {}
+        }
+        if !self.effects.is_active(Priority::Indication) {
+            if let Some((effect, duration_ms)) = self.next_indication.take() {
+                self.effects
+                    .set_for(Priority::Indication, effect, now, duration_ms);
+            }
+        }
+        let warning = self.effects.is_active(Priority::BatteryWarning)
+            || self.effects.is_active(Priority::SplitError);
+        let frame = match self.custom {
+            Some(custom) if !warning => custom,
+            _ => [self.effects.frame(now); N],
+        }
+        .map(|color| color.scale(self.brightness).gamma_corrected());
+        let dark = frame.iter().all(|&color| color == Rgb::BLACK);
+        if dark || self.is_idle(now) || self.battery_is_critical() {
+            self.power_off();
+            return;
+        }
+        self.power_on().await;
+        self.set_frame(frame);
+        if self.dirty {
+            self.show().await;
+        }
     }
 }
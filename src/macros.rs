@@ -10,3 +10,53 @@ macro_rules! config_matrix_pins_nrf {
         }
     };
 }
+
+/// Takes the pins of one half out of `peripherals`, as a `board::PinMap`.
+///
+/// Every pin is named once in the map, and the build fails if one shows up twice.
+macro_rules! pin_map {
+    (
+        peripherals: $p:ident,
+        matrix_input: [$($in_pin:ident),+ $(,)?],
+        matrix_output: [$($out_pin:ident),+ $(,)?],
+        encoder: [$enc_a:ident, $enc_b:ident],
+        led_data: $led_data:ident,
+        led_power: $led_power:ident,
+        battery_adc: $adc_pin:ident,
+        $(charge_detect: $charge_pin:ident,)?
+    ) => {
+        {
+            const _: () = $crate::board::assert_unique_pins(&[
+                $(stringify!($in_pin),)+
+                $(stringify!($out_pin),)+
+                stringify!($enc_a),
+                stringify!($enc_b),
+                stringify!($led_data),
+                stringify!($led_power),
+                stringify!($adc_pin),
+                $(stringify!($charge_pin),)?
+            ]);
+            let (input, output) = config_matrix_pins_nrf!(peripherals: $p, input: [$($in_pin),+], output: [$($out_pin),+]);
+            $crate::board::PinMap {
+                matrix: $crate::board::MatrixPins { input, output },
+                encoder: $crate::board::EncoderPins {
+                    a: Input::new($p.$enc_a, embassy_nrf::gpio::Pull::None),
+                    b: Input::new($p.$enc_b, embassy_nrf::gpio::Pull::None),
+                },
+                led_data: $p.$led_data.into(),
+                led_power: $p.$led_power.into(),
+                sleep: $crate::board::SleepPins {
+                    rows: [$($crate::board::pin_number(stringify!($in_pin))),+],
+                    cols: [$($crate::board::pin_number(stringify!($out_pin))),+],
+                    led_power: $crate::board::pin_number(stringify!($led_power)),
+                },
+                battery_adc: embassy_nrf::saadc::Input::degrade_saadc($p.$adc_pin),
+                charge_detect: pin_map!(@charge_detect $p $(, $charge_pin)?),
+            }
+        }
+    };
+    (@charge_detect $p:ident) => { None };
+    (@charge_detect $p:ident, $charge_pin:ident) => {
+        Some(Input::new($p.$charge_pin, embassy_nrf::gpio::Pull::Up))
+    };
+}
@@ -3,166 +3,106 @@
 
 #[macro_use]
 mod macros;
+mod activity;
+mod battery;
+mod board;
+mod build_info;
 mod constants;
-
-use crate::constants::{INPUT_PIN_NUM, L2CAP_MTU, L2CAP_RXQ, L2CAP_TXQ, OUTPUT_PIN_NUM};
-use defmt::{info, unwrap};
+mod led;
+mod sleep;
+mod split_guard;
+mod split_peripheral;
+
+use crate::activity::KeyActivity;
+use crate::constants::{
+    INPUT_PIN_NUM, LED_BRIGHTNESS, LED_IDLE_TIMEOUT, LED_NUM, OUTPUT_PIN_NUM,
+    PERIPHERAL_ENCODER_ID, PERIPHERAL_ID, SLEEP_CENTRAL_TIMEOUT, SLEEP_IDLE_TIMEOUT,
+    STORAGE_NUM_SECTORS, STORAGE_START_ADDR,
+};
+use crate::led::LedController;
+use crate::sleep::SleepController;
+use crate::split_guard::KeyGate;
+use crate::split_peripheral::Handshake;
+use defmt::info;
 use embassy_executor::Spawner;
-use embassy_nrf::gpio::{Input, Output};
-use embassy_nrf::interrupt::{self, InterruptExt};
-use embassy_nrf::mode::Async;
-use embassy_nrf::peripherals::{RNG, SAADC, USBD};
-use embassy_nrf::saadc::{self, AnyInput, Input as _, Saadc};
-use embassy_nrf::{Peri, bind_interrupts, rng, usb};
-use nrf_mpsl::Flash;
-use nrf_sdc::mpsl::MultiprotocolServiceLayer;
-use nrf_sdc::{self as sdc, mpsl};
-use rand_chacha::ChaCha12Rng;
-use rand_core::SeedableRng;
 use rmk::ble::build_ble_stack;
 use rmk::channel::EVENT_CHANNEL;
 use rmk::config::StorageConfig;
+use rmk::controller::PollingController;
 use rmk::debounce::default_debouncer::DefaultDebouncer;
-use rmk::futures::future::join;
+use rmk::futures::future::join4;
 use rmk::input_device::rotary_encoder::RotaryEncoder;
 use rmk::matrix::Matrix;
 use rmk::split::peripheral::run_rmk_split_peripheral;
 use rmk::storage::new_storage_for_split_peripheral;
 use rmk::{HostResources, run_devices};
-use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
 
-bind_interrupts!(struct Irqs {
-    USBD => usb::InterruptHandler<USBD>;
-    SAADC => saadc::InterruptHandler;
-    RNG => rng::InterruptHandler<RNG>;
-    EGU0_SWI0 => nrf_sdc::mpsl::LowPrioInterruptHandler;
-    CLOCK_POWER => nrf_sdc::mpsl::ClockInterruptHandler, usb::vbus_detect::InterruptHandler;
-    RADIO => nrf_sdc::mpsl::HighPrioInterruptHandler;
-    TIMER0 => nrf_sdc::mpsl::HighPrioInterruptHandler;
-    RTC0 => nrf_sdc::mpsl::HighPrioInterruptHandler;
-});
-
-#[embassy_executor::task]
-async fn mpsl_task(mpsl: &'static MultiprotocolServiceLayer<'static>) -> ! {
-    mpsl.run().await
-}
-
-fn build_sdc<'d, const N: usize>(
-    p: nrf_sdc::Peripherals<'d>,
-    rng: &'d mut rng::Rng<RNG, Async>,
-    mpsl: &'d MultiprotocolServiceLayer,
-    mem: &'d mut sdc::Mem<N>,
-) -> Result<nrf_sdc::SoftdeviceController<'d>, nrf_sdc::Error> {
-    sdc::Builder::new()?
-        .support_adv()?
-        .support_peripheral()?
-        .support_dle_peripheral()?
-        .support_phy_update_peripheral()?
-        .support_le_2m_phy()?
-        .peripheral_count(1)?
-        .buffer_cfg(L2CAP_MTU as u16, L2CAP_MTU as u16, L2CAP_TXQ, L2CAP_RXQ)?
-        .build(p, rng, mpsl, mem)
-}
-
-/// Initializes the SAADC peripheral in single-ended mode on the given pin.
-fn init_adc(adc_pin: AnyInput, adc: Peri<'static, SAADC>) -> Saadc<'static, 1> {
-    // Then we initialize the ADC. We are only using one channel in this example.
-    let config = saadc::Config::default();
-    let channel_cfg = saadc::ChannelConfig::single_ended(adc_pin.degrade_saadc());
-    interrupt::SAADC.set_priority(interrupt::Priority::P3);
-    let saadc = saadc::Saadc::new(adc, Irqs, config, [channel_cfg]);
-    saadc
-}
-
-fn ble_addr() -> [u8; 6] {
-    let ficr = embassy_nrf::pac::FICR;
-    let high = u64::from(ficr.deviceid(1).read());
-    let addr = high << 32 | u64::from(ficr.deviceid(0).read());
-    let addr = addr | 0x0000_c000_0000_0000;
-    unwrap!(addr.to_le_bytes()[..6].try_into())
-}
-
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     info!("Hello RMK BLE!");
-    // Initialize the peripherals and nrf-sdc controller
-    let mut nrf_config = embassy_nrf::config::Config::default();
-    nrf_config.dcdc.reg0_voltage = Some(embassy_nrf::config::Reg0Voltage::_3V3);
-    nrf_config.dcdc.reg0 = true;
-    nrf_config.dcdc.reg1 = true;
-    let p = embassy_nrf::init(nrf_config);
-    let mpsl_p =
-        mpsl::Peripherals::new(p.RTC0, p.TIMER0, p.TEMP, p.PPI_CH19, p.PPI_CH30, p.PPI_CH31);
-    let lfclk_cfg = mpsl::raw::mpsl_clock_lfclk_cfg_t {
-        source: mpsl::raw::MPSL_CLOCK_LF_SRC_RC as u8,
-        rc_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_CTIV as u8,
-        rc_temp_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_TEMP_CTIV as u8,
-        accuracy_ppm: mpsl::raw::MPSL_DEFAULT_CLOCK_ACCURACY_PPM as u16,
-        skip_wait_lfclk_started: mpsl::raw::MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
-    };
-    static MPSL: StaticCell<MultiprotocolServiceLayer> = StaticCell::new();
-    static SESSION_MEM: StaticCell<mpsl::SessionMem<1>> = StaticCell::new();
-    let mpsl = MPSL.init(unwrap!(mpsl::MultiprotocolServiceLayer::with_timeslots(
-        mpsl_p,
-        Irqs,
-        lfclk_cfg,
-        SESSION_MEM.init(mpsl::SessionMem::new())
-    )));
-    spawner.must_spawn(mpsl_task(&*mpsl));
-    let sdc_p = sdc::Peripherals::new(
-        p.PPI_CH17, p.PPI_CH18, p.PPI_CH20, p.PPI_CH21, p.PPI_CH22, p.PPI_CH23, p.PPI_CH24,
-        p.PPI_CH25, p.PPI_CH26, p.PPI_CH27, p.PPI_CH28, p.PPI_CH29,
-    );
-    let mut rng = rng::Rng::new(p.RNG, Irqs);
-    let mut rng_generator = ChaCha12Rng::from_rng(&mut rng).unwrap();
-    let mut sdc_mem = sdc::Mem::<8192>::new();
-    let sdc = unwrap!(build_sdc(sdc_p, &mut rng, mpsl, &mut sdc_mem));
-
+    build_info::log_build_info();
+    let board::Peripheral {
+        mut ble,
+        flash,
+        battery_adc: _,
+        matrix: matrix_pins,
+        encoder: encoder_pins,
+        led: led_pins,
+        sleep: sleep_pins,
+    } = board::init_peripheral(spawner).await;
     let mut resources = HostResources::new();
-    let stack = build_ble_stack(sdc, ble_addr(), &mut rng_generator, &mut resources).await;
+    let stack = build_ble_stack(ble.sdc, ble.addr, &mut ble.rng, &mut resources).await;
 
-    // Initialize the ADC. We are only using one channel for detecting battery level
-    let adc_pin = p.P0_05.degrade_saadc();
-    let saadc = init_adc(adc_pin, p.SAADC);
-    // Wait for ADC calibration.
-    saadc.calibrate().await;
-
-    let (input_pins, output_pins) = config_matrix_pins_nrf!(peripherals: p, input: [P1_09, P0_28, P0_03, P1_10], output:  [P0_09, P0_10, P1_13, P0_02, P0_29, P0_31, P0_30]);
-
-    // FLASH : ORIGIN = 0x00001000, LENGTH = 1020K
-    // RAM : ORIGIN = 0x20000008, LENGTH = 255K
-    // Initialize flash
-    // nRF52840's bootloader starts from 0xF4000(976K)
+    // Initialize flash, the storage region comes from `[memory]` in keyboard.toml
     let storage_config = StorageConfig {
-        start_addr: 0xA0000, // 384K
-        num_sectors: 32,     // 128K
+        start_addr: STORAGE_START_ADDR,
+        num_sectors: STORAGE_NUM_SECTORS,
         clear_storage: false,
         ..Default::default()
     };
-    let flash = Flash::take(mpsl, p.NVMC);
     let mut storage = new_storage_for_split_peripheral(flash, storage_config).await;
 
     // Initialize the peripheral matrix
     let debouncer = DefaultDebouncer::<{ INPUT_PIN_NUM }, { OUTPUT_PIN_NUM }>::new();
-    let mut matrix = Matrix::<_, _, _, { INPUT_PIN_NUM }, { OUTPUT_PIN_NUM }>::new(
-        input_pins,
-        output_pins,
+    let matrix = Matrix::<_, _, _, { INPUT_PIN_NUM }, { OUTPUT_PIN_NUM }>::new(
+        matrix_pins.input,
+        matrix_pins.output,
         debouncer,
     );
-    // let mut matrix = rmk::matrix::TestMatrix::<4, 7>::new();
+    // let matrix = rmk::matrix::TestMatrix::<4, 7>::new();
+    // The LED's and sleep's idle timeouts go by key presses, which only the central publishes otherwise
+    // Key presses stop while the central refuses this half
+    let mut matrix = KeyGate::new(KeyActivity::new(matrix));
+
+    let encoder = RotaryEncoder::with_resolution(
+        encoder_pins.a,
+        encoder_pins.b,
+        4,
+        true,
+        PERIPHERAL_ENCODER_ID,
+    );
+    let mut encoder = KeyGate::new(encoder);
+
+    let mut led = LedController::<_, { LED_NUM }>::new(led_pins.pwm, led_pins.data, led_pins.power)
+        .with_brightness(LED_BRIGHTNESS)
+        .with_idle_timeout(LED_IDLE_TIMEOUT);
+    let mut sleep = SleepController::new(sleep_pins)
+        .with_idle_timeout(SLEEP_IDLE_TIMEOUT)
+        .with_central_timeout(SLEEP_CENTRAL_TIMEOUT);
 
-    let pin_a = Input::new(p.P1_06, embassy_nrf::gpio::Pull::None);
-    let pin_b = Input::new(p.P1_04, embassy_nrf::gpio::Pull::None);
-    let mut encoder = RotaryEncoder::with_resolution(pin_a, pin_b, 4, true, 1);
+    // Tells the central which build this is, and finds out if it refuses it
+    let mut handshake = Handshake::new(PERIPHERAL_ID);
 
     // Start
-    join(
+    join4(
         run_devices! (
-            (matrix, encoder) => EVENT_CHANNEL, // Peripheral uses EVENT_CHANNEL to send events to central
+            (matrix, encoder, handshake) => EVENT_CHANNEL, // Peripheral uses EVENT_CHANNEL to send events to central
         ),
-        run_rmk_split_peripheral(0, &stack, &mut storage),
+        run_rmk_split_peripheral(PERIPHERAL_ID, &stack, &mut storage),
+        led.polling_loop(),
+        sleep.polling_loop(),
     )
     .await;
 }
@@ -0,0 +1,143 @@
+//! Deep sleep: puts the nRF52840 into System OFF when the keyboard isn't used.
+//!
+//! Each half sleeps after a while without key presses on it. The peripheral also
+//! follows once it has lost the central for long enough, which is also what
+//! happens when the central goes to sleep. With the dongle the link never drops,
+//! so there only the idle timeout applies. A key press on the matrix wakes the
+//! chip, which then boots from scratch.
+
+use defmt::{info, unwrap};
+use embassy_nrf::pac;
+use embassy_nrf::pac::gpio::vals::{Dir, Drive, Input, Pull, Sense};
+use embassy_time::{Duration, Instant, Timer};
+use rmk::channel::{CONTROLLER_CHANNEL, ControllerSub};
+use rmk::controller::{Controller, PollingController};
+use rmk::event::ControllerEvent;
+
+use crate::board::SleepPins;
+
+pub struct SleepController {
+    sub: ControllerSub,
+    pins: SleepPins,
+    /// Sleep after this long without key presses
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+    /// Sleep after the central has been gone for this long
+    central_timeout: Option<Duration>,
+    /// When the central went away, `None` while it's connected
+    central_lost_at: Option<Instant>,
+}
+
+impl SleepController {
+    pub fn new(pins: SleepPins) -> Self {
+        let now = Instant::now();
+        Self {
+            sub: unwrap!(CONTROLLER_CHANNEL.subscriber()),
+            pins,
+            idle_timeout: None,
+            last_activity: now,
+            central_timeout: None,
+            // Not connected yet
+            central_lost_at: Some(now),
+        }
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// For the peripheral, sleep when the central has been disconnected for `timeout`
+    pub fn with_central_timeout(mut self, timeout: Duration) -> Self {
+        self.central_timeout = Some(timeout);
+        self
+    }
+
+    fn should_sleep(&self, now: Instant) -> bool {
+        // Keep USB up while it powers the keyboard
+        if pac::POWER.usbregstatus().read().vbusdetect() {
+            return false;
+        }
+        let idle = self
+            .idle_timeout
+            .is_some_and(|timeout| now - self.last_activity >= timeout);
+        let central_gone = match (self.central_timeout, self.central_lost_at) {
+            (Some(timeout), Some(lost_at)) => now - lost_at >= timeout,
+            _ => false,
+        };
+        idle || central_gone
+    }
+
+    /// Arms the matrix for wake up and enters System OFF, the chip resets on wake up
+    async fn system_off(&self) -> ! {
+        info!("No activity, entering System OFF");
+        // Let the log go out
+        Timer::after_millis(10).await;
+
+        for &pin in self.pins.cols.iter() {
+            let (port, n) = port_pin(pin);
+            port.outset().write(|w| w.set_pin(n, true));
+            port.pin_cnf(n).write(|w| {
+                w.set_dir(Dir::OUTPUT);
+                w.set_input(Input::DISCONNECT);
+                w.set_drive(Drive::S0S1);
+            });
+        }
+        for &pin in self.pins.rows.iter() {
+            let (port, n) = port_pin(pin);
+            port.pin_cnf(n).write(|w| {
+                w.set_dir(Dir::INPUT);
+                w.set_input(Input::CONNECT);
+                w.set_pull(Pull::PULLDOWN);
+                w.set_sense(Sense::HIGH);
+            });
+        }
+        // GPIO state holds in System OFF, so switch the LED rail off
+        let (port, n) = port_pin(self.pins.led_power);
+        port.outclr().write(|w| w.set_pin(n, true));
+
+        pac::POWER.systemoff().write(|w| w.set_systemoff(true));
+        // System OFF is only emulated while a debugger is attached
+        loop {
+            cortex_m::asm::wfe();
+        }
+    }
+}
+
+/// GPIO port and index of a pin numbered by `board::pin_number`
+fn port_pin(pin: u8) -> (pac::gpio::Gpio, usize) {
+    let port = if pin < 32 { pac::P0 } else { pac::P1 };
+    (port, (pin % 32) as usize)
+}
+
+impl Controller for SleepController {
+    type Event = ControllerEvent;
+
+    async fn process_event(&mut self, event: Self::Event) {
+        match event {
+            ControllerEvent::Key(event, _) if event.pressed => self.last_activity = Instant::now(),
+            ControllerEvent::SplitCentral(connected) => {
+                self.central_lost_at = if connected {
+                    None
+                } else {
+                    Some(Instant::now())
+                };
+            }
+            _ => {}
+        }
+    }
+
+    async fn next_message(&mut self) -> Self::Event {
+        self.sub.next_message_pure().await
+    }
+}
+
+impl PollingController for SleepController {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    async fn update(&mut self) {
+        if self.should_sleep(Instant::now()) {
+            self.system_off().await;
+        }
+    }
+}
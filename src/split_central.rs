@@ -0,0 +1,191 @@
+//! The central's side of the messages in `cornix_core::split`.
+//!
+//! The central refuses a peripheral whose `Hello` doesn't match it, or that sends
+//! none within `HELLO_TIMEOUT_MS` of connecting, and then drops its link for the
+//! rest of the boot. Every peripheral is refused on its own, the other link stays
+//! up.
+
+use core::cell::RefCell;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cornix_core::split::{HELLO_TIMEOUT_MS, Message, Mismatch, PROTOCOL, Placement, check_hello};
+use defmt::{error, info, unwrap, warn};
+use embassy_time::{Duration, Instant, Timer};
+use rmk::channel::CONTROLLER_CHANNEL;
+use rmk::event::{ControllerEvent, Event};
+use rmk::futures::future::{Either, select};
+use rmk::input_device::{InputProcessor, ProcessResult};
+use rmk::keymap::KeyMap;
+
+use crate::build_info::BUILD_VERSION;
+
+/// The dongle has the most peripherals, both halves
+const MAX_PERIPHERALS: usize = 2;
+
+/// Refused peripherals, by peripheral id
+static REFUSED: [AtomicBool; MAX_PERIPHERALS] = [const { AtomicBool::new(false) }; MAX_PERIPHERALS];
+/// Peripherals whose `Hello` was accepted since they connected, by peripheral id
+static GREETED: [AtomicBool; MAX_PERIPHERALS] = [const { AtomicBool::new(false) }; MAX_PERIPHERALS];
+
+fn refuse(peripheral_id: usize) {
+    if let Some(refused) = REFUSED.get(peripheral_id) {
+        if !refused.swap(true, Ordering::Relaxed) {
+            warn!("Dropping peripheral {}'s link", peripheral_id);
+        }
+    }
+}
+
+fn is_refused(peripheral_id: usize) -> bool {
+    REFUSED
+        .get(peripheral_id)
+        .is_some_and(|refused| refused.load(Ordering::Relaxed))
+}
+
+fn set_greeted(peripheral_id: usize, greeted: bool) {
+    if let Some(flag) = GREETED.get(peripheral_id) {
+        flag.store(greeted, Ordering::Relaxed);
+    }
+}
+
+fn is_greeted(peripheral_id: usize) -> bool {
+    GREETED
+        .get(peripheral_id)
+        .is_some_and(|greeted| greeted.load(Ordering::Relaxed))
+}
+
+/// Runs a peripheral's link until the central refuses that peripheral. Dropping
+/// the link disconnects the peripheral, and it stays down for the rest of the boot.
+///
+/// A peripheral that connects and sends no `Hello` within `HELLO_TIMEOUT_MS`,
+/// like one with a build from before the handshake, is refused too.
+pub async fn until_refused(peripheral_id: usize, link: impl Future) {
+    let refused = async {
+        let mut sub = unwrap!(CONTROLLER_CHANNEL.subscriber());
+        // When the peripheral has to have sent its `Hello`, while it's connected
+        let mut hello_deadline: Option<Instant> = None;
+        while !is_refused(peripheral_id) {
+            let next = pin!(sub.next_message_pure());
+            match select(next, Timer::after_millis(100)).await {
+                Either::Left((ControllerEvent::SplitPeripheral(id, connected), _))
+                    if id == peripheral_id =>
+                {
+                    if connected {
+                        hello_deadline =
+                            Some(Instant::now() + Duration::from_millis(HELLO_TIMEOUT_MS));
+                    } else {
+                        hello_deadline = None;
+                        set_greeted(peripheral_id, false);
+                    }
+                }
+                _ => {}
+            }
+            match hello_deadline {
+                Some(deadline) if Instant::now() >= deadline => {
+                    hello_deadline = None;
+                    if !is_greeted(peripheral_id) {
+                        error!(
+                            "Refusing peripheral {}, it sent no hello within {}ms",
+                            peripheral_id, HELLO_TIMEOUT_MS
+                        );
+                        refuse(peripheral_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+    };
+    select(pin!(link), pin!(refused)).await;
+}
+
+/// Takes the peripherals' messages out of the event chain and checks their builds
+pub struct PeripheralMonitor<
+    'a,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+    const NUM_ENCODER: usize,
+> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>>,
+    /// Where each peripheral's matrix should sit in the keymap, by peripheral id
+    placements: &'static [Placement],
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize, const NUM_ENCODER: usize>
+    PeripheralMonitor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+{
+    pub fn new(
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>>,
+        placements: &'static [Placement],
+    ) -> Self {
+        Self { keymap, placements }
+    }
+
+    fn handle(&mut self, message: Message) {
+        let peripheral_id = message.peripheral_id();
+        if is_greeted(peripheral_id as usize) {
+            // The peripheral repeats its `Hello` in case one gets lost
+            return;
+        }
+        if let Message::Hello { version, .. } = message {
+            info!(
+                "Peripheral {} runs {}.{}.{} ({=[u8]:02x})",
+                peripheral_id,
+                version.major,
+                version.minor,
+                version.patch,
+                &version.commit[..]
+            );
+            if version != BUILD_VERSION {
+                warn!(
+                    "Peripheral {} runs another build than the central, flash both from the same commit",
+                    peripheral_id
+                );
+            }
+        }
+
+        let Some(&expected) = self.placements.get(peripheral_id as usize) else {
+            // There's no telling which link it came over
+            error!("Refusing unknown peripheral {}", peripheral_id);
+            (0..MAX_PERIPHERALS).for_each(refuse);
+            return;
+        };
+        let peripheral_id = peripheral_id as usize;
+        match check_hello(&message, expected) {
+            Ok(()) => set_greeted(peripheral_id, true),
+            Err(Mismatch::Protocol(protocol)) => {
+                error!(
+                    "Refusing peripheral {}, it speaks split protocol {} and the central {}",
+                    peripheral_id, protocol, PROTOCOL
+                );
+                refuse(peripheral_id);
+            }
+            Err(Mismatch::Placement(placement)) => {
+                error!(
+                    "Refusing peripheral {}, its matrix is at {} but the central expects {}",
+                    peripheral_id, placement, expected
+                );
+                refuse(peripheral_id);
+            }
+        }
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize, const NUM_ENCODER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+    for PeripheralMonitor<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        if let Event::Custom(payload) = event {
+            if let Some(message) = Message::decode(&payload) {
+                self.handle(message);
+                return ProcessResult::Stop;
+            }
+        }
+        ProcessResult::Continue(event)
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER, NUM_ENCODER>> {
+        self.keymap
+    }
+}
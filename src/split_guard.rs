@@ -0,0 +1,53 @@
+//! Dropping key presses while the split link is refused.
+//!
+//! The central refuses a peripheral whose build doesn't match it, see
+//! `split_central`, and stops its own keys along with it. The peripheral can't be
+//! told why, so it takes a link that drops right after its `Hello` as refused.
+//! While refused, a half drops key presses and the status LED shows the error.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn};
+use rmk::event::Event;
+use rmk::input_device::InputDevice;
+
+static REFUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_refused(refused: bool) {
+    if REFUSED.swap(refused, Ordering::Relaxed) != refused {
+        if refused {
+            warn!("Split link refused, dropping key presses");
+        } else {
+            info!("Split link accepted");
+        }
+    }
+}
+
+pub fn is_refused() -> bool {
+    REFUSED.load(Ordering::Relaxed)
+}
+
+/// Wraps a matrix or an encoder, dropping everything but key releases while the
+/// link is refused. Releases still go through, so no key stays held.
+pub struct KeyGate<D: InputDevice> {
+    device: D,
+}
+
+impl<D: InputDevice> KeyGate<D> {
+    pub fn new(device: D) -> Self {
+        Self { device }
+    }
+}
+
+impl<D: InputDevice> InputDevice for KeyGate<D> {
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let event = self.device.read_event().await;
+            match event {
+                _ if !is_refused() => return event,
+                Event::Key(key) if !key.pressed => return event,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! The peripheral's side of the messages in `cornix_core::split`.
+//!
+//! They're input devices in the peripheral's `run_devices!`, RMK forwards their
+//! `Event::Custom` to the central along with the keys.
+
+use core::pin::pin;
+
+use cornix_core::split::{HELLO_TIMEOUT_MS, Message};
+use defmt::{info, unwrap};
+use embassy_time::{Duration, Instant, Timer};
+use rmk::channel::{CONTROLLER_CHANNEL, ControllerSub};
+use rmk::event::{ControllerEvent, Event};
+use rmk::futures::future::{Either, select};
+use rmk::input_device::InputDevice;
+
+use crate::build_info::BUILD_VERSION;
+use crate::constants::PERIPHERAL_PLACEMENT;
+use crate::split_guard::set_refused;
+
+/// RMK only forwards events once it has marked the link as up, which can be just
+/// after `SplitCentral(true)` is published
+const HELLO_DELAY: Duration = Duration::from_millis(200);
+
+/// The `Hello` goes out this many times, this far apart, in case one gets lost.
+/// The last one is still well within the central's `HELLO_TIMEOUT_MS`.
+const HELLO_COUNT: u8 = 3;
+const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A link that drops this soon after connecting was refused by the central
+const REFUSAL_WINDOW: Duration = Duration::from_millis(HELLO_TIMEOUT_MS + 1000);
+
+/// Sends `Message::Hello` with this build every time the central connects, and
+/// refuses the link if the central drops it right after
+pub struct Handshake {
+    sub: ControllerSub,
+    peripheral_id: u8,
+    /// When the central connected, until the link has held for `REFUSAL_WINDOW`
+    connected_at: Option<Instant>,
+    /// How many times the `Hello` went out since then
+    hellos_sent: u8,
+}
+
+impl Handshake {
+    pub fn new(peripheral_id: usize) -> Self {
+        Self {
+            sub: unwrap!(CONTROLLER_CHANNEL.subscriber()),
+            peripheral_id: peripheral_id as u8,
+            connected_at: None,
+            hellos_sent: 0,
+        }
+    }
+
+    fn hello(peripheral_id: u8) -> Event {
+        let hello = Message::Hello {
+            peripheral_id,
+            version: BUILD_VERSION,
+            placement: PERIPHERAL_PLACEMENT,
+        };
+        Event::Custom(hello.encode())
+    }
+}
+
+impl InputDevice for Handshake {
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let event = match self.connected_at {
+                Some(connected_at) => {
+                    let next_hello = (self.hellos_sent < HELLO_COUNT).then(|| {
+                        connected_at + HELLO_DELAY + HELLO_INTERVAL * self.hellos_sent as u32
+                    });
+                    let wake_at = next_hello.unwrap_or(connected_at + REFUSAL_WINDOW);
+                    let next = pin!(self.sub.next_message_pure());
+                    match select(next, Timer::at(wake_at)).await {
+                        Either::Left((event, _)) => event,
+                        Either::Right(_) if next_hello.is_some() => {
+                            if self.hellos_sent == 0 {
+                                info!("Connected to the central, sending hello");
+                            }
+                            self.hellos_sent += 1;
+                            return Self::hello(self.peripheral_id);
+                        }
+                        Either::Right(_) => {
+                            // The central kept the link, so it took the `Hello`
+                            self.connected_at = None;
+                            set_refused(false);
+                            continue;
+                        }
+                    }
+                }
+                None => self.sub.next_message_pure().await,
+            };
+            match event {
+                ControllerEvent::SplitCentral(true) => {
+                    self.connected_at = Some(Instant::now());
+                    self.hellos_sent = 0;
+                }
+                ControllerEvent::SplitCentral(false) => {
+                    if self.connected_at.take().is_some() && self.hellos_sent > 0 {
+                        set_refused(true);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
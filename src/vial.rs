@@ -2,10 +2,11 @@ use rmk::config::VialConfig;
 
 // Vial config is automatically generated by `build.rs`, according to `vial.json`
 // Please put `vial.json` at your project's root
+// The unlock keys come from `[security]` in `keyboard.toml`
 include!(concat!(env!("OUT_DIR"), "/config_generated.rs"));
 
 pub const VIAL_CONFIG: VialConfig = VialConfig {
     vial_keyboard_id: &VIAL_KEYBOARD_ID,
     vial_keyboard_def: &VIAL_KEYBOARD_DEF,
-    unlock_keys: &[(1, 1), (1, 2)],
+    unlock_keys: VIAL_UNLOCK_KEYS,
 };